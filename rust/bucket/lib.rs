@@ -0,0 +1,89 @@
+use std::cell::RefCell;
+
+use candid::Nat;
+use ic_cdk::{caller, trap};
+use ic_cdk::export::candid::candid_method;
+use ic_cdk::export::Principal;
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
+use num_traits::ToPrimitive;
+use sld::env::CanisterEnvironment;
+use sld::icrc3::{BlockRange, GetBlocksResult};
+use sld::types::{AddBlockError, BlockOrBucket, Event, EventOrBucket};
+
+use crate::state::State;
+
+mod state;
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::default());
+}
+
+#[query]
+#[candid_method(query)]
+fn sld3_get_tx(tx_id: Nat) -> Option<EventOrBucket> {
+    STATE.with(|s| s.borrow().read_tx(tx_id))
+}
+
+#[query]
+#[candid_method(query)]
+fn sld3_get_block(block_id: Nat) -> Option<BlockOrBucket> {
+    STATE.with(|s| s.borrow().read_block(block_id))
+}
+
+#[update]
+#[candid_method(update)]
+fn sld3_add_block(block: Vec<Event>) -> Result<(), AddBlockError> {
+    STATE.with(|s| s.borrow_mut().add_block(caller(), block))
+}
+
+#[query]
+#[candid_method(query)]
+fn sld3_block_size() -> Nat {
+    STATE.with(|s| Nat::from(s.borrow().block_size))
+}
+
+#[query]
+#[candid_method(query)]
+fn sld3_tx_range() -> (Nat, Nat) {
+    STATE.with(|s| s.borrow().tx_range())
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc3_get_blocks(args: Vec<BlockRange>) -> GetBlocksResult {
+    STATE.with(|s| s.borrow().get_blocks(args))
+}
+
+#[init]
+#[candid_method(init)]
+fn init(ledger: Principal, block_size: Nat, first_block: Nat) {
+    let block_size = block_size.0
+        .to_usize()
+        .filter(|block_size| *block_size > 0)
+        .unwrap_or_else(|| trap("Block size should be a positive number"));
+    let first_block = first_block.0
+        .to_usize()
+        .unwrap_or_else(|| trap("First block is out of range"));
+    STATE.with(|s| s.borrow_mut().init(ledger, block_size, first_block));
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    // Blocks already live in stable memory, only the snapshot is written after them
+    STATE.with(|s| s.take().save())
+        .unwrap_or_else(|err| trap(&format!("An error occurred when saving to stable memory (pre_upgrade): {}", err)));
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let state = State::restore(Box::new(CanisterEnvironment::default()))
+        .unwrap_or_else(|err| trap(&format!("An error occurred when restoring from stable memory (post_upgrade): {}", err)));
+    STATE.with(|s| s.replace(state));
+}
+
+#[query(name = "__get_candid_interface_tmp_hack")]
+fn export_candid() -> String {
+    __export_service()
+}
+
+candid::export_service!();
@@ -0,0 +1,294 @@
+use candid::Nat;
+use ic_cdk::export::candid::CandidType;
+use ic_cdk::export::Principal;
+use ic_cdk::trap;
+use num_traits::ToPrimitive;
+use serde::Deserialize;
+use sld::env::{CanisterEnvironment, Environment};
+use sld::icrc3::{self, BlockRange, BlockWithId, GetBlocksResult};
+use sld::stable::{HEADER_BYTES, stable_restore, stable_restore_header, stable_save, stable_save_header, StableReader, StableWriter};
+use sld::state::{MAX_BLOCKS_PER_REQUEST, MAX_PAGE_BYTES};
+use sld::types::{AddBlockError, BlockOrBucket, Event, EventOrBucket, GenericError};
+
+/// Bucket holds a consecutive range of sealed blocks offloaded by the ledger, starting
+/// at `first_block`. Blocks are written to stable memory one after the other like the
+/// ledger seals them, only the offset at which each block ends is kept on the heap.
+pub struct State {
+    pub ledger: Option<Principal>,
+    pub block_size: usize,
+    pub first_block: usize,
+    /// Stable memory offset at which each block ends
+    pub block_indexes: Vec<usize>,
+    /// Environment the state runs in, never persisted
+    pub env: Box<dyn Environment>,
+}
+
+/// Snapshot of the state that is written after the blocks in stable memory during upgrades
+#[derive(CandidType, Deserialize)]
+pub struct StableState {
+    pub ledger: Option<Principal>,
+    pub block_size: usize,
+    pub first_block: usize,
+    pub block_indexes: Vec<usize>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State::with_env(Box::new(CanisterEnvironment::default()))
+    }
+}
+
+impl State {
+    pub fn with_env(env: Box<dyn Environment>) -> Self {
+        State {
+            ledger: None,
+            block_size: 0,
+            first_block: 0,
+            block_indexes: Vec::default(),
+            env,
+        }
+    }
+
+    pub fn init(&mut self, ledger: Principal, block_size: usize, first_block: usize) {
+        self.ledger = Some(ledger);
+        self.block_size = block_size;
+        self.first_block = first_block;
+    }
+
+    /// Write the snapshot after the blocks in memory, the header points to where it starts
+    pub fn save(self) -> Result<(), String> {
+        let offset = self.stable_offset();
+        let stable_state = StableState {
+            ledger: self.ledger,
+            block_size: self.block_size,
+            first_block: self.first_block,
+            block_indexes: self.block_indexes,
+        };
+        stable_save_header(self.env.memory(), offset).map_err(|err| format!("{:?}", err))?;
+        stable_save(self.env.memory(), (stable_state, ), offset).map_err(|err| format!("{:?}", err))
+    }
+
+    /// Read the snapshot that the header points to from the memory of the given environment
+    pub fn restore(env: Box<dyn Environment>) -> Result<Self, String> {
+        let offset = stable_restore_header(env.memory()).map_err(|err| format!("{:?}", err))?;
+        let (stable_state, ) = stable_restore::<(StableState, )>(env.memory(), offset)?;
+        Ok(State {
+            ledger: stable_state.ledger,
+            block_size: stable_state.block_size,
+            first_block: stable_state.first_block,
+            block_indexes: stable_state.block_indexes,
+            env,
+        })
+    }
+
+    /// Offset in stable memory where the next block or the snapshot is written
+    pub fn stable_offset(&self) -> usize {
+        self.block_indexes.last().copied().unwrap_or(HEADER_BYTES)
+    }
+
+    pub fn tx_range(&self) -> (Nat, Nat) {
+        let start = self.first_block * self.block_size;
+        (Nat::from(start), Nat::from(start + self.block_indexes.len() * self.block_size))
+    }
+
+    pub fn add_block(&mut self, caller: Principal, block: Vec<Event>) -> Result<(), AddBlockError> {
+        if self.ledger != Some(caller) {
+            return Err(AddBlockError::NotAllowed);
+        }
+        // Only sealed blocks are offloaded, so every block should be complete
+        if block.len() != self.block_size {
+            return Err(AddBlockError::GenericError(GenericError {
+                error_code: Nat::from(400),
+                message: format!("Block should contain {} transactions", self.block_size),
+            }));
+        }
+        let bytes = candid::encode_one(&block)
+            .unwrap_or_else(|err| trap(&format!("An error occurred when encoding block: {:?}", err)));
+        let mut writer = StableWriter::new(self.env.memory(), self.stable_offset());
+        writer.write(&bytes)
+            .unwrap_or_else(|err| trap(&format!("An error occurred when writing to stable memory: {:?}", err)));
+        self.block_indexes.push(writer.offset);
+
+        Ok(())
+    }
+
+    pub fn read_tx(&self, tx_id: Nat) -> Option<EventOrBucket> {
+        let tx_id = tx_id.0.to_usize()?;
+        match self.read_block(Nat::from(tx_id / self.block_size))? {
+            BlockOrBucket::Block(block) => block
+                .get(tx_id % self.block_size)
+                .map(|event| EventOrBucket::Event(event.clone())),
+            BlockOrBucket::Bucket(bucket) => Some(EventOrBucket::Bucket(bucket))
+        }
+    }
+
+    /// Blocks are read from stable memory, each block starts where the previous block has ended
+    pub fn read_block(&self, block_id: Nat) -> Option<BlockOrBucket> {
+        let index = block_id.0.to_usize()?.checked_sub(self.first_block)?;
+        let end = *self.block_indexes.get(index)?;
+        let start = if index == 0 { HEADER_BYTES } else { self.block_indexes[index - 1] };
+        let mut reader = StableReader::new(self.env.memory(), start);
+        let mut bytes = vec![0u8; end - start];
+        reader.read(&mut bytes)
+            .unwrap_or_else(|err| trap(&format!("An error occurred when reading from stable memory: {:?}", err)));
+        let block = candid::decode_one::<Vec<Event>>(&bytes)
+            .unwrap_or_else(|err| trap(&format!("An error occurred when decoding block: {:?}", err)));
+        Some(BlockOrBucket::Block(block))
+    }
+
+    /// Transactions within the given ranges as ICRC-3 blocks, ranges
+    /// are cut off at the transactions that are held by this bucket.
+    pub fn get_blocks(&self, args: Vec<BlockRange>) -> GetBlocksResult {
+        let start = self.first_block * self.block_size;
+        let end = start + self.block_indexes.len() * self.block_size;
+        let mut blocks = vec![];
+        let mut bytes = 0;
+
+        // Consecutive transactions are mostly in the same block, which is only read once
+        let mut current: Option<(usize, Vec<Event>)> = None;
+        'ranges: for range in args {
+            let range_start = range.start.0.to_usize().unwrap_or(usize::MAX).max(start);
+            let range_end = range.start.0.to_usize().unwrap_or(usize::MAX)
+                .saturating_add(range.length.0.to_usize().unwrap_or(usize::MAX))
+                .min(end);
+            for tx_id in range_start..range_end {
+                let block_id = tx_id / self.block_size;
+                if current.as_ref().map(|(id, _)| *id) != Some(block_id) {
+                    current = match self.read_block(Nat::from(block_id)) {
+                        Some(BlockOrBucket::Block(block)) => Some((block_id, block)),
+                        _ => break 'ranges
+                    };
+                }
+                let block = match &current {
+                    Some((_, block)) => icrc3::block(&block[tx_id % self.block_size]),
+                    None => break 'ranges
+                };
+                bytes += candid::encode_one(&block).map_or(0, |block| block.len());
+                if blocks.len() == MAX_BLOCKS_PER_REQUEST || bytes > MAX_PAGE_BYTES {
+                    break 'ranges;
+                }
+                blocks.push(BlockWithId { id: Nat::from(tx_id), block });
+            }
+        }
+        GetBlocksResult {
+            log_length: Nat::from(end),
+            blocks,
+            archived_blocks: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    use sld::env::MockEnvironment;
+    use sld::memory::Memory;
+    use sld::types::Value;
+
+    use super::*;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    fn ledger() -> Principal {
+        principal(1)
+    }
+
+    /// Transaction events only differ in their time, which is the transaction id
+    fn event(tx_id: usize) -> Event {
+        Event {
+            caller: principal(2),
+            operation: "sld1:transfer".into(),
+            time: tx_id as u64,
+            details: HashMap::from([("token_id".into(), Value::Nat(Nat::from(tx_id)))]),
+        }
+    }
+
+    fn block(block_size: usize, block_id: usize) -> Vec<Event> {
+        (block_id * block_size..(block_id + 1) * block_size).map(event).collect()
+    }
+
+    /// Bucket that holds blocks 3 up to 5 of 2 transactions each
+    fn init_state() -> (State, Rc<MockEnvironment>) {
+        let env = Rc::new(MockEnvironment::default());
+        let mut state = State::with_env(Box::new(env.clone()));
+        state.init(ledger(), 2, 3);
+        for block_id in 3..6 {
+            state.add_block(ledger(), block(2, block_id)).unwrap();
+        }
+        (state, env)
+    }
+
+    fn times(block: Option<BlockOrBucket>) -> Vec<u64> {
+        match block {
+            Some(BlockOrBucket::Block(block)) => block.iter().map(|event| event.time).collect(),
+            _ => panic!("Block should be held by the bucket"),
+        }
+    }
+
+    #[test]
+    fn add_block() {
+        let (mut state, env) = init_state();
+        assert_eq!(state.tx_range(), (Nat::from(6), Nat::from(12)));
+        assert!(env.memory.size() > 0);
+
+        assert!(matches!(state.add_block(principal(2), block(2, 6)), Err(AddBlockError::NotAllowed)));
+        assert!(matches!(state.add_block(ledger(), vec![event(12)]), Err(AddBlockError::GenericError(_))));
+        assert_eq!(state.block_indexes.len(), 3);
+    }
+
+    #[test]
+    fn read_block() {
+        let (state, _) = init_state();
+        assert_eq!(times(state.read_block(Nat::from(3))), vec![6, 7]);
+        assert_eq!(times(state.read_block(Nat::from(5))), vec![10, 11]);
+        assert!(state.read_block(Nat::from(2)).is_none());
+        assert!(state.read_block(Nat::from(6)).is_none());
+    }
+
+    #[test]
+    fn read_tx() {
+        let (state, _) = init_state();
+        for tx_id in 6..12 {
+            match state.read_tx(Nat::from(tx_id)) {
+                Some(EventOrBucket::Event(event)) => assert_eq!(event.time, tx_id),
+                _ => panic!("Transaction {} should be held by the bucket", tx_id),
+            }
+        }
+        assert!(state.read_tx(Nat::from(5)).is_none());
+        assert!(state.read_tx(Nat::from(12)).is_none());
+    }
+
+    #[test]
+    fn get_blocks() {
+        let (state, _) = init_state();
+        let result = state.get_blocks(vec![
+            BlockRange { start: Nat::from(0), length: Nat::from(8) },
+            BlockRange { start: Nat::from(11), length: Nat::from(100) },
+        ]);
+        assert_eq!(result.log_length, Nat::from(12));
+        let ids: Vec<Nat> = result.blocks.iter().map(|block| block.id.clone()).collect();
+        assert_eq!(ids, vec![Nat::from(6), Nat::from(7), Nat::from(11)]);
+        assert_eq!(result.blocks[0].block, icrc3::block(&event(6)));
+    }
+
+    #[test]
+    fn save_and_restore() {
+        let (state, env) = init_state();
+        let offset = state.stable_offset();
+        state.save().unwrap();
+
+        let mut state = State::restore(Box::new(env)).unwrap();
+        assert_eq!((state.ledger, state.block_size, state.first_block), (Some(ledger()), 2, 3));
+        assert_eq!(state.stable_offset(), offset);
+        assert_eq!(times(state.read_block(Nat::from(4))), vec![8, 9]);
+
+        // Blocks that are added after an upgrade overwrite the snapshot
+        state.add_block(ledger(), block(2, 6)).unwrap();
+        assert_eq!(times(state.read_block(Nat::from(6))), vec![12, 13]);
+        assert_eq!(state.tx_range(), (Nat::from(6), Nat::from(14)));
+    }
+}
@@ -0,0 +1,37 @@
+//! Calls to the SLD-3 bucket canisters that sealed blocks are offloaded to.
+use candid::Nat;
+use ic_cdk::api::call::call;
+use ic_cdk::export::Principal;
+
+use crate::types::{AddBlockError, Event, GenericError, OffloadBlocksError};
+
+fn call_error(method: &str, (code, message): (ic_cdk::api::call::RejectionCode, String)) -> OffloadBlocksError {
+    OffloadBlocksError::GenericError(GenericError {
+        error_code: Nat::from(code as u32),
+        message: format!("Call to bucket {} failed: {}", method, message),
+    })
+}
+
+pub async fn block_size(bucket: Principal) -> Result<Nat, OffloadBlocksError> {
+    call::<_, (Nat, )>(bucket, "sld3_block_size", ())
+        .await
+        .map(|(block_size, )| block_size)
+        .map_err(|err| call_error("sld3_block_size", err))
+}
+
+pub async fn tx_range(bucket: Principal) -> Result<(Nat, Nat), OffloadBlocksError> {
+    call::<_, (Nat, Nat)>(bucket, "sld3_tx_range", ())
+        .await
+        .map_err(|err| call_error("sld3_tx_range", err))
+}
+
+pub async fn add_block(bucket: Principal, block: Vec<Event>) -> Result<(), OffloadBlocksError> {
+    let (result, ) = call::<_, (Result<(), AddBlockError>, )>(bucket, "sld3_add_block", (block, ))
+        .await
+        .map_err(|err| call_error("sld3_add_block", err))?;
+    result.map_err(|err| match err {
+        AddBlockError::NotAllowed => OffloadBlocksError::NotAllowed,
+        AddBlockError::TemporarilyUnavailable => OffloadBlocksError::TemporarilyUnavailable,
+        AddBlockError::GenericError(generic_error) => OffloadBlocksError::GenericError(generic_error)
+    })
+}
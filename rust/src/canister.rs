@@ -0,0 +1,559 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+#[cfg(feature = "sld2")]
+use std::collections::HashSet;
+
+use candid::Nat;
+use ic_cdk::api::call::{ManualReply, msg_cycles_accept128, msg_cycles_available128};
+use ic_cdk::{caller, trap};
+use ic_cdk::export::candid::candid_method;
+#[cfg(any(feature = "sld2", feature = "sld3", feature = "sld6"))]
+use ic_cdk::export::Principal;
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
+
+#[cfg(feature = "sld3")]
+use crate::bucket;
+#[cfg(feature = "sld3")]
+use crate::icrc3::{ArchiveInfo, BlockRange, GetArchivesArgs, GetBlocksResult, SupportedBlockType};
+use crate::env::CanisterEnvironment;
+use crate::state::State;
+#[cfg(feature = "sld3")]
+use crate::tx::OPERATIONS;
+use crate::types::{Account, HttpRequest, HttpResponse, InitArgs, StreamingCallbackHttpResponse, StreamingCallbackToken, SupportedStandard, TokenId, TransferArgs, TransferError, TransferFromArgs, UpgradeArgs, Value};
+#[cfg(feature = "sld2")]
+use crate::types::{ApproveArgs, ApproveError, SetApprovalArgs, SetApprovalError, SetApprovalForAllArgs, SetApprovalForAllError, TransferFromError};
+#[cfg(feature = "sld3")]
+use crate::types::{BlockOrBucket, DataCertificate, EventOrBucket, GenericError, OffloadBlocksError};
+#[cfg(feature = "sld4")]
+use crate::types::{MintArgs, MintError};
+#[cfg(feature = "sld5")]
+use crate::types::{BurnArgs, BurnError};
+#[cfg(feature = "sld6")]
+use crate::types::{SetCustodianArgs, SetCustodiansError, SetMetadataArgs, SetMetadataError};
+#[cfg(feature = "sld7")]
+use crate::types::RoyaltyFee;
+#[cfg(feature = "sld8")]
+use crate::types::{SetRoyaltyFeeArgs, SetRoyaltyFeeError};
+
+/// Standards that are implemented by this canister
+const SUPPORTED_STANDARDS: &[&str] = &[
+    "SLD-1",
+    #[cfg(feature = "sld2")]
+    "SLD-2",
+    #[cfg(feature = "sld3")]
+    "SLD-3",
+    #[cfg(feature = "sld4")]
+    "SLD-4",
+    #[cfg(feature = "sld5")]
+    "SLD-5",
+    #[cfg(feature = "sld6")]
+    "SLD-6",
+    #[cfg(feature = "sld7")]
+    "SLD-7",
+    #[cfg(feature = "sld8")]
+    "SLD-8",
+];
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::default());
+}
+
+#[query(manual_reply = true)]
+#[candid_method(query)]
+fn sld1_metadata() -> ManualReply<HashMap<String, Value>> {
+    STATE.with(|s| ManualReply::one(&s.borrow().metadata))
+}
+
+#[query(manual_reply = true)]
+#[candid_method(query)]
+fn sld1_name() -> ManualReply<String> {
+    STATE.with(|s| ManualReply::one(&s.borrow().name))
+}
+
+#[query(manual_reply = true)]
+#[candid_method(query)]
+fn sld1_symbol() -> ManualReply<String> {
+    STATE.with(|s| ManualReply::one(&s.borrow().symbol))
+}
+
+#[query]
+#[candid_method(query)]
+fn sld1_total_supply() -> Nat {
+    STATE.with(|s| s.borrow().total_supply())
+}
+
+#[query]
+#[candid_method(query)]
+fn sld1_minting_account() -> Account {
+    STATE.with(|s| s.borrow().minting_account())
+}
+
+#[query]
+#[candid_method(query)]
+fn sld1_balance_of(account: Account) -> Nat {
+    STATE.with(|s| s.borrow().balance_of(&account))
+}
+
+#[query]
+#[candid_method(query)]
+fn sld1_owner_of(token_id: TokenId) -> Option<Account> {
+    STATE.with(|s| s.borrow().owner_of(&token_id))
+}
+
+#[query(manual_reply = true)]
+#[candid_method(query)]
+fn sld1_metadata_of(token_id: TokenId) -> ManualReply<Option<HashMap<String, Value>>> {
+    STATE.with(|s| ManualReply::one(s.borrow().metadata_of(&token_id)))
+}
+
+#[query(manual_reply = true)]
+#[candid_method(query)]
+fn sld1_tokens(page: Nat) -> ManualReply<Vec<TokenId>> {
+    STATE.with(|s| ManualReply::one(s.borrow().tokens(&page)))
+}
+
+#[query(manual_reply = true)]
+#[candid_method(query)]
+fn sld1_tokens_of(account: Account, page: Nat) -> ManualReply<Vec<TokenId>> {
+    STATE.with(|s| ManualReply::one(s.borrow().tokens_of(&account, &page)))
+}
+
+#[query(manual_reply = true)]
+#[candid_method(query)]
+fn sld1_tokens_after(start_after: Option<TokenId>, limit: Option<Nat>) -> ManualReply<Vec<TokenId>> {
+    STATE.with(|s| ManualReply::one(s.borrow().tokens_after(start_after.as_ref(), limit.as_ref())))
+}
+
+#[query(manual_reply = true)]
+#[candid_method(query)]
+fn sld1_tokens_of_after(account: Account, start_after: Option<TokenId>, limit: Option<Nat>) -> ManualReply<Vec<TokenId>> {
+    STATE.with(|s| ManualReply::one(s.borrow().tokens_of_after(&account, start_after.as_ref(), limit.as_ref())))
+}
+
+#[update]
+#[candid_method(update)]
+fn sld1_transfer(args: TransferArgs) -> Result<Nat, TransferError> {
+    STATE.with(|s| s.borrow_mut().transfer(
+        TransferFromArgs {
+            from: Account::new(caller(), args.from_subaccount),
+            to: Account::new(args.to.owner, args.to.subaccount),
+            token_id: args.token_id,
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+        }
+    ))
+}
+
+#[query]
+#[candid_method(query)]
+fn sld1_supported_standards() -> Vec<SupportedStandard> {
+    SUPPORTED_STANDARDS
+        .iter()
+        .map(|name| SupportedStandard {
+            name: name.to_string(),
+            url: "https://github.com/slide-computer/slide-token".into(),
+        })
+        .collect()
+}
+
+#[cfg(feature = "sld2")]
+#[update]
+#[candid_method(update)]
+fn sld2_set_approval(args: SetApprovalArgs) -> Result<Nat, SetApprovalError> {
+    STATE.with(|s| s.borrow_mut().set_approval(args))
+}
+
+/// Deprecated, replaced by `sld2_set_approval`
+#[cfg(feature = "sld2")]
+#[update]
+#[candid_method(update)]
+fn sld2_approve(args: ApproveArgs) -> Result<Nat, ApproveError> {
+    STATE.with(|s| s.borrow_mut().approve(args))
+}
+
+#[cfg(feature = "sld2")]
+#[update]
+#[candid_method(update)]
+fn sld2_transfer_from(args: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    STATE.with(|s| s.borrow_mut().transfer_from(
+        TransferFromArgs {
+            from: Account::new(args.from.owner, args.from.subaccount),
+            to: Account::new(args.to.owner, args.to.subaccount),
+            token_id: args.token_id,
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+        }
+    ))
+}
+
+#[cfg(feature = "sld2")]
+#[query(manual_reply = true)]
+#[candid_method(query)]
+fn sld2_get_approved(token_id: TokenId) -> ManualReply<HashSet<Principal>> {
+    STATE.with(|s| ManualReply::one(s.borrow().get_approved(&token_id)))
+}
+
+#[cfg(feature = "sld2")]
+#[update]
+#[candid_method(update)]
+fn sld2_set_approval_for_all(args: SetApprovalForAllArgs) -> Result<Nat, SetApprovalForAllError> {
+    STATE.with(|s| s.borrow_mut().set_approval_for_all(args))
+}
+
+#[cfg(feature = "sld2")]
+#[query(manual_reply = true)]
+#[candid_method(query)]
+fn sld2_get_approved_for_all(account: Account) -> ManualReply<HashSet<Principal>> {
+    STATE.with(|s| ManualReply::one(s.borrow().get_approved_for_all(&account)))
+}
+
+#[cfg(feature = "sld3")]
+#[query]
+#[candid_method(query)]
+fn sld3_get_tx(tx_id: Nat) -> Option<EventOrBucket> {
+    STATE.with(|s| s.borrow().read_tx(tx_id))
+}
+
+#[cfg(feature = "sld3")]
+#[query]
+#[candid_method(query)]
+fn sld3_get_block(block_id: Nat) -> Option<BlockOrBucket> {
+    STATE.with(|s| s.borrow().read_block(block_id))
+}
+
+#[cfg(feature = "sld3")]
+#[query]
+#[candid_method(query)]
+fn sld3_block_size() -> Nat {
+    STATE.with(|s| Nat::from(s.borrow().block_size))
+}
+
+#[cfg(feature = "sld3")]
+#[update]
+#[candid_method(update)]
+async fn sld3_offload_blocks(bucket: Principal) -> Result<Nat, OffloadBlocksError> {
+    STATE.with(|s| s.borrow_mut().start_offload(caller()))?;
+    let _guard = OffloadGuard;
+    offload_blocks(bucket).await
+}
+
+/// Ends the offload when dropped, the future is also dropped when a callback
+/// traps, so the log is never left locked for offloading after a failed call.
+#[cfg(feature = "sld3")]
+struct OffloadGuard;
+
+#[cfg(feature = "sld3")]
+impl Drop for OffloadGuard {
+    fn drop(&mut self) {
+        STATE.with(|s| s.borrow_mut().end_offload());
+    }
+}
+
+/// Offload all sealed blocks to the bucket, the bucket should
+/// continue exactly where previously offloaded blocks have ended.
+#[cfg(feature = "sld3")]
+async fn offload_blocks(bucket: Principal) -> Result<Nat, OffloadBlocksError> {
+    let (block_size, offloaded_blocks) = STATE.with(|s| {
+        let s = s.borrow();
+        (s.block_size, s.offloaded_blocks())
+    });
+    if bucket::block_size(bucket).await? != block_size {
+        return Err(OffloadBlocksError::GenericError(GenericError {
+            error_code: Nat::from(400),
+            message: "Bucket block size does not match".into(),
+        }));
+    }
+    let (_, end) = bucket::tx_range(bucket).await?;
+    if end != offloaded_blocks * block_size {
+        return Err(OffloadBlocksError::GenericError(GenericError {
+            error_code: Nat::from(400),
+            message: "Bucket does not continue where the offloaded blocks have ended".into(),
+        }));
+    }
+
+    let mut count: usize = 0;
+    while let Some((block_id, block)) = STATE.with(|s| s.borrow().next_offload_block()) {
+        bucket::add_block(bucket, block).await?;
+        STATE.with(|s| s.borrow_mut().offload_block(bucket, block_id));
+        count += 1;
+    }
+
+    Ok(Nat::from(count))
+}
+
+#[cfg(feature = "sld3")]
+#[query(manual_reply = true)]
+#[candid_method(query)]
+fn sld3_tx_total() -> ManualReply<Nat> {
+    STATE.with(|s| ManualReply::one(&s.borrow().tx_total))
+}
+
+/// Certificate of the index and hash of the last transaction
+#[cfg(feature = "sld3")]
+#[query]
+#[candid_method(query)]
+fn sld3_get_tip_certificate() -> Option<DataCertificate> {
+    STATE.with(|s| s.borrow().tip_certificate())
+}
+
+#[cfg(feature = "sld3")]
+#[query]
+#[candid_method(query)]
+fn icrc3_get_blocks(args: Vec<BlockRange>) -> GetBlocksResult {
+    STATE.with(|s| s.borrow().get_blocks(args))
+}
+
+#[cfg(feature = "sld3")]
+#[query]
+#[candid_method(query)]
+fn icrc3_get_archives(args: GetArchivesArgs) -> Vec<ArchiveInfo> {
+    STATE.with(|s| s.borrow().get_archives(args.from))
+}
+
+#[cfg(feature = "sld3")]
+#[query]
+#[candid_method(query)]
+fn icrc3_get_tip_certificate() -> Option<DataCertificate> {
+    STATE.with(|s| s.borrow().tip_certificate())
+}
+
+#[cfg(feature = "sld3")]
+#[query]
+#[candid_method(query)]
+fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+    OPERATIONS
+        .iter()
+        .map(|block_type| SupportedBlockType {
+            block_type: block_type.to_string(),
+            url: "https://github.com/slide-computer/slide-token".into(),
+        })
+        .collect()
+}
+
+#[cfg(feature = "sld4")]
+#[update]
+#[candid_method(update)]
+fn sld4_mint(args: MintArgs) -> Result<Nat, MintError> {
+    STATE.with(|s| s.borrow_mut().mint(args))
+}
+
+#[cfg(feature = "sld5")]
+#[update]
+#[candid_method(update)]
+fn sld5_burn(args: BurnArgs) -> Result<Nat, BurnError> {
+    STATE.with(|s| s.borrow_mut().burn(args))
+}
+
+#[cfg(feature = "sld6")]
+#[query(manual_reply = true)]
+#[candid_method(query)]
+fn sld6_get_custodians() -> ManualReply<Vec<Principal>> {
+    STATE.with(|s| ManualReply::one(&s.borrow().custodians))
+}
+
+#[cfg(feature = "sld6")]
+#[update]
+#[candid_method(update)]
+fn sld6_set_custodian(args: SetCustodianArgs) -> Result<Nat, SetCustodiansError> {
+    STATE.with(|s| s.borrow_mut().set_custodian(args))
+}
+
+#[cfg(feature = "sld6")]
+#[update]
+#[candid_method(update)]
+fn sld6_set_metadata(args: SetMetadataArgs) -> Result<Nat, SetMetadataError> {
+    STATE.with(|s| s.borrow_mut().set_metadata(args))
+}
+
+#[cfg(feature = "sld6")]
+#[update]
+#[candid_method(update)]
+fn sld6_remove_metadata(key: String) -> Result<Nat, SetMetadataError> {
+    STATE.with(|s| s.borrow_mut().remove_metadata(key))
+}
+
+#[cfg(feature = "sld6")]
+#[update]
+#[candid_method(update)]
+fn sld6_freeze_metadata(key: String) -> Result<Nat, SetMetadataError> {
+    STATE.with(|s| s.borrow_mut().freeze_metadata(key))
+}
+
+#[cfg(feature = "sld7")]
+#[query]
+#[candid_method(query)]
+fn sld7_royalty_fee(price: Nat) -> RoyaltyFee {
+    STATE.with(|s| s.borrow().royalty_fee(None, &price))
+}
+
+/// Extension of SLD-7 for tokens with their own royalty fee
+#[cfg(feature = "sld7")]
+#[query]
+#[candid_method(query)]
+fn sld7_royalty_fee_of(token_id: TokenId, price: Nat) -> RoyaltyFee {
+    STATE.with(|s| s.borrow().royalty_fee(Some(&token_id), &price))
+}
+
+#[cfg(feature = "sld8")]
+#[update]
+#[candid_method(update)]
+fn sld8_set_royalty_fee(args: SetRoyaltyFeeArgs) -> Result<Nat, SetRoyaltyFeeError> {
+    STATE.with(|s| s.borrow_mut().set_royalty_fee(args))
+}
+
+/// Deprecated, replaced by `sld6_get_custodians`
+#[cfg(feature = "sld6")]
+#[query(manual_reply = true)]
+#[candid_method(query)]
+fn sld4_get_custodians() -> ManualReply<Vec<Principal>> {
+    STATE.with(|s| ManualReply::one(&s.borrow().custodians))
+}
+
+/// Deprecated, replaced by `sld6_set_custodian`
+#[cfg(feature = "sld6")]
+#[update]
+#[candid_method(update)]
+fn sld4_set_custodian(args: SetCustodianArgs) -> Result<Nat, SetCustodiansError> {
+    STATE.with(|s| s.borrow_mut().set_custodian(args))
+}
+
+
+#[query]
+#[candid_method(query)]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    STATE.with(|s| s.borrow().http_request(req))
+}
+
+#[query]
+#[candid_method(query)]
+fn http_request_streaming_callback(token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+    STATE.with(|s| s.borrow().http_request_streaming_callback(token))
+}
+
+#[query]
+#[candid_method(query)]
+fn cycles() -> Nat {
+    STATE.with(|s| Nat::from(s.borrow().env.cycles()))
+}
+
+#[update]
+#[candid_method(update)]
+fn wallet_receive() {
+    let amount = msg_cycles_available128();
+    if amount > 0 {
+        msg_cycles_accept128(amount);
+    }
+}
+
+#[init]
+#[candid_method(init)]
+fn init(args: InitArgs) {
+    STATE.with(|s| s.borrow_mut().init(args)).unwrap_or_else(|err| trap(&err));
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    // Snapshot is written after the data that already lives in stable memory
+    STATE.with(|s| s.take().save())
+        .unwrap_or_else(|err| trap(&format!("An error occurred when saving to stable memory (pre_upgrade): {}", err)));
+}
+
+#[post_upgrade]
+fn post_upgrade(args: Option<UpgradeArgs>) {
+    STATE.with(|s| {
+        // Reconstruct state with hash tree from stable state
+        let state = State::restore(Box::new(CanisterEnvironment::default()))
+            .unwrap_or_else(|err| trap(&format!("An error occurred when restoring from stable memory (post_upgrade): {}", err)));
+        s.replace(state);
+        if let Some(args) = args {
+            s.borrow_mut().upgrade(args).unwrap_or_else(|err| trap(&err));
+        }
+    });
+}
+
+#[query(name = "__get_candid_interface_tmp_hack")]
+fn export_candid() -> String {
+    __export_service()
+}
+
+candid::export_service!();
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use candid::parser::types::IDLProg;
+    use candid::types::Type;
+    use candid::utils::{CandidSource, service_compatible};
+    use candid::{check_prog, TypeEnv};
+
+    use super::*;
+
+    /// Standards on top of SLD-1, whether they're enabled and a method that is only exported when they are
+    const STANDARDS: &[(&str, bool, &str)] = &[
+        ("SLD-2", cfg!(feature = "sld2"), "sld2_transfer_from"),
+        ("SLD-3", cfg!(feature = "sld3"), "icrc3_get_blocks"),
+        ("SLD-4", cfg!(feature = "sld4"), "sld4_mint"),
+        ("SLD-5", cfg!(feature = "sld5"), "sld5_burn"),
+        ("SLD-6", cfg!(feature = "sld6"), "sld6_set_custodian"),
+        ("SLD-6", cfg!(feature = "sld6"), "sld6_set_metadata"),
+        ("SLD-7", cfg!(feature = "sld7"), "sld7_royalty_fee"),
+        ("SLD-8", cfg!(feature = "sld8"), "sld8_set_royalty_fee"),
+    ];
+
+    /// Methods of the exported service
+    fn methods() -> Vec<String> {
+        let prog: IDLProg = __export_service().parse().unwrap();
+        let mut env = TypeEnv::new();
+        let actor = check_prog(&mut env, &prog).unwrap().unwrap();
+        let service = match actor {
+            Type::Class(_, service) => *service,
+            service => service,
+        };
+        env.as_service(&service).unwrap().iter().map(|(method, _)| method.clone()).collect()
+    }
+
+    #[test]
+    fn supported_standards() {
+        let names: Vec<String> = sld1_supported_standards().into_iter().map(|standard| standard.name).collect();
+        assert_eq!(names[0], "SLD-1");
+        for (name, enabled, _) in STANDARDS {
+            assert_eq!(names.iter().any(|standard| standard == name), *enabled, "{} should be supported if and only if it's enabled", name);
+        }
+    }
+
+    #[test]
+    fn exported_candid() {
+        let methods = methods();
+        assert!(methods.iter().any(|method| method == "sld1_transfer"));
+        for (name, enabled, method) in STANDARDS {
+            assert_eq!(methods.iter().any(|exported| exported == method), *enabled, "{} should be exported if and only if {} is enabled", method, name);
+        }
+    }
+
+    /// sld.did that dfx deploys with is the exported service of a ledger with every standard,
+    /// run the test with `UPDATE_CANDID=1` to write the exported service to sld.did instead.
+    #[cfg(all(feature = "sld2", feature = "sld3", feature = "sld4", feature = "sld5", feature = "sld6", feature = "sld7", feature = "sld8"))]
+    #[test]
+    fn published_candid() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("sld.did");
+        let exported = __export_service() + "\n";
+        if std::env::var_os("UPDATE_CANDID").is_some() {
+            std::fs::write(&path, &exported).unwrap();
+        }
+        let published = std::fs::read_to_string(&path).unwrap();
+        assert!(published == exported, "sld.did differs from the exported service, run the test with UPDATE_CANDID=1 to update it");
+    }
+
+    /// Exported service can be used wherever one of the supported standards is expected
+    #[test]
+    fn standards_compatible() {
+        let candid = __export_service();
+        for name in SUPPORTED_STANDARDS {
+            let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(format!("../../standards/{0}/{0}.did", name));
+            service_compatible(CandidSource::Text(&candid), CandidSource::File(&path))
+                .unwrap_or_else(|err| panic!("Exported candid is not a subtype of {}: {}", name, err));
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! This module contains the [Environment] that the state runs in, so that the state
+//! doesn't call the system API directly and can also be used outside a canister.
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use ic_cdk::export::Principal;
+
+use crate::memory::{Memory, StableMemory, VecMemory};
+
+pub trait Environment {
+    /// Principal that called the current method
+    fn caller(&self) -> Principal;
+
+    /// Current time in nanoseconds since the UNIX epoch
+    fn time(&self) -> u64;
+
+    /// Principal of the canister itself
+    fn id(&self) -> Principal;
+
+    /// Cycles balance of the canister
+    fn cycles(&self) -> u128;
+
+    /// Set the data that is certified by the subnet, only available in update calls
+    fn set_certified_data(&self, data: &[u8]);
+
+    /// Certificate of the certified data, only available in query calls
+    fn data_certificate(&self) -> Option<Vec<u8>>;
+
+    /// Memory that sealed blocks and the state snapshot are written to
+    fn memory(&self) -> &dyn Memory;
+}
+
+/// Environment of a canister running on the Internet Computer, by default the ledger
+/// uses stable memory from the first page. Canisters that embed the ledger and keep their
+/// own data in stable memory give it a [StableMemory] that starts after their own pages.
+#[derive(Default)]
+pub struct CanisterEnvironment {
+    pub memory: StableMemory,
+}
+
+impl Environment for CanisterEnvironment {
+    fn caller(&self) -> Principal {
+        ic_cdk::caller()
+    }
+
+    fn time(&self) -> u64 {
+        ic_cdk::api::time()
+    }
+
+    fn id(&self) -> Principal {
+        ic_cdk::id()
+    }
+
+    fn cycles(&self) -> u128 {
+        ic_cdk::api::canister_balance128()
+    }
+
+    fn set_certified_data(&self, data: &[u8]) {
+        ic_cdk::api::set_certified_data(data)
+    }
+
+    fn data_certificate(&self) -> Option<Vec<u8>> {
+        ic_cdk::api::data_certificate()
+    }
+
+    fn memory(&self) -> &dyn Memory {
+        &self.memory
+    }
+}
+
+/// Environment with fixed values that can be changed at will, the certified
+/// data is kept as is and returned as certificate to inspect what was certified.
+///
+/// Memory lives on the heap, share the environment with an [Rc] to change
+/// values or inspect the memory while the state runs against it.
+pub struct MockEnvironment {
+    pub caller: Cell<Principal>,
+    pub time: Cell<u64>,
+    pub id: Principal,
+    pub cycles: Cell<u128>,
+    pub certified_data: RefCell<Vec<u8>>,
+    pub memory: VecMemory,
+}
+
+impl Default for MockEnvironment {
+    fn default() -> Self {
+        MockEnvironment {
+            caller: Cell::new(Principal::anonymous()),
+            time: Cell::default(),
+            id: Principal::management_canister(),
+            cycles: Cell::default(),
+            certified_data: RefCell::default(),
+            memory: VecMemory::default(),
+        }
+    }
+}
+
+impl Environment for MockEnvironment {
+    fn caller(&self) -> Principal {
+        self.caller.get()
+    }
+
+    fn time(&self) -> u64 {
+        self.time.get()
+    }
+
+    fn id(&self) -> Principal {
+        self.id
+    }
+
+    fn cycles(&self) -> u128 {
+        self.cycles.get()
+    }
+
+    fn set_certified_data(&self, data: &[u8]) {
+        *self.certified_data.borrow_mut() = data.to_vec();
+    }
+
+    fn data_certificate(&self) -> Option<Vec<u8>> {
+        Some(self.certified_data.borrow().clone())
+    }
+
+    fn memory(&self) -> &dyn Memory {
+        &self.memory
+    }
+}
+
+impl<T: Environment + ?Sized> Environment for Rc<T> {
+    fn caller(&self) -> Principal {
+        (**self).caller()
+    }
+
+    fn time(&self) -> u64 {
+        (**self).time()
+    }
+
+    fn id(&self) -> Principal {
+        (**self).id()
+    }
+
+    fn cycles(&self) -> u128 {
+        (**self).cycles()
+    }
+
+    fn set_certified_data(&self, data: &[u8]) {
+        (**self).set_certified_data(data)
+    }
+
+    fn data_certificate(&self) -> Option<Vec<u8>> {
+        (**self).data_certificate()
+    }
+
+    fn memory(&self) -> &dyn Memory {
+        (**self).memory()
+    }
+}
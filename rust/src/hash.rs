@@ -0,0 +1,104 @@
+//! Representation-independent hashing of transactions, a hash only depends on the
+//! values themselves so that clients can verify it without knowing how it was encoded.
+//!
+//! Nats and ints are hashed as (s)leb128, text and blobs as their bytes, arrays as the concatenation
+//! of their element hashes and maps as the sorted concatenation of the key hash followed by the value
+//! hash of each entry, as defined in ICRC-3.
+use candid::{Int, Nat};
+use ic_certified_map::{Hash, HashTree};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::icrc3;
+use crate::types::{Event, Value};
+
+/// Key of the parent hash in the details of every transaction but the first
+pub const PARENT_HASH_KEY: &str = "phash";
+
+pub fn hash_bytes(bytes: &[u8]) -> Hash {
+    Sha256::digest(bytes).into()
+}
+
+pub fn hash_nat(nat: &Nat) -> Hash {
+    let mut bytes = vec![];
+    nat.encode(&mut bytes).unwrap();
+    hash_bytes(&bytes)
+}
+
+pub fn hash_int(int: &Int) -> Hash {
+    let mut bytes = vec![];
+    int.encode(&mut bytes).unwrap();
+    hash_bytes(&bytes)
+}
+
+pub fn hash_array(hashes: impl Iterator<Item=Hash>) -> Hash {
+    hash_bytes(&hashes.collect::<Vec<_>>().concat())
+}
+
+pub fn hash_map<'a>(entries: impl Iterator<Item=(&'a str, Hash)>) -> Hash {
+    let mut entries: Vec<[u8; 64]> = entries.map(|(key, hash)| {
+        let mut entry = [0; 64];
+        entry[..32].copy_from_slice(&hash_bytes(key.as_bytes()));
+        entry[32..].copy_from_slice(&hash);
+        entry
+    }).collect();
+    entries.sort_unstable();
+    hash_bytes(&entries.concat())
+}
+
+pub fn hash_value(value: &Value) -> Hash {
+    match value {
+        Value::Nat(value) => hash_nat(value),
+        Value::Int(value) => hash_int(value),
+        Value::Text(value) => hash_bytes(value.as_bytes()),
+        Value::Blob(value) => hash_bytes(value),
+        Value::Array(values) => hash_array(values.iter().map(hash_value)),
+        Value::Map(values) => hash_map(values.iter().map(|(key, value)| (key.as_str(), hash_value(value)))),
+    }
+}
+
+/// Hash of a transaction, which is the hash of the transaction as ICRC-3 block
+pub fn hash_event(event: &Event) -> Hash {
+    hash_value(&icrc3::block(event))
+}
+
+/// Self-describing CBOR encoding of a hash tree, as expected by certificate verification
+pub fn encode_tree(tree: &HashTree) -> Vec<u8> {
+    let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+    serializer.self_describe().unwrap();
+    tree.serialize(&mut serializer).unwrap();
+    serializer.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn hash_value_vectors() {
+        // Values and hashes as given in the ICRC-3 standard
+        let vectors = [
+            (Value::Nat(Nat::from(42)), "684888c0ebb17f374298b65ee2807526c066094c701bcc7ebbe1c1095f494fc1"),
+            (Value::Int(Int::from(-42)), "de5a6f78116eca62d7fc5ce159d23ae6b889b365a1739ad2cf36f925a140d0cc"),
+            (Value::Text("Hello, World!".into()), "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f"),
+            (Value::Blob(vec![1, 2, 3, 4]), "9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a"),
+            (
+                Value::Array(vec![Value::Nat(Nat::from(3)), Value::Text("foo".into()), Value::Blob(vec![5, 6])]),
+                "514a04011caa503990d446b7dec5d79e19c221ae607fb08b2848c67734d468d6",
+            ),
+            (
+                Value::Map(HashMap::from([
+                    ("amount".to_string(), Value::Nat(Nat::from(42))),
+                    ("memo".to_string(), Value::Text("hi".into())),
+                    ("tags".to_string(), Value::Array(vec![Value::Text("a".into()), Value::Text("b".into())])),
+                ])),
+                "4bbdc4a3fadc635d709a83525f2a97c3caf22622e084a6366aa98861277bfda4",
+            ),
+        ];
+        for (value, hash) in vectors {
+            assert_eq!(hex::encode(hash_value(&value)), hash, "{:?}", value);
+        }
+    }
+}
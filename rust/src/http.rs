@@ -0,0 +1,116 @@
+//! JSON encoding of the state that is served by the certified http interface.
+//!
+//! Nats are encoded as strings since they don't necessarily fit in a JSON number, map keys are
+//! sorted so that the same state always results in the same body and thus the same hash.
+use std::collections::HashMap;
+
+use candid::{Int, Nat};
+use ic_cdk::export::Principal;
+use ic_certified_map::{Hash, HashTree};
+use serde_json::{json, Map, Value as Json};
+use sha2::Digest;
+
+use crate::hash;
+use crate::types::{Event, HeaderField, Token, TokenId, Value};
+
+/// Bodies larger than a single chunk are streamed, stays below
+/// the 2MiB reply size limit including the response headers.
+pub const MAX_CHUNK_BYTES: usize = 1_900_000;
+
+/// Token ids are served in pages of consecutive token ids, so that minting
+/// or burning a token only changes the page that the token id is on.
+pub const TOKENS_PER_PAGE: u64 = 1_000;
+
+/// Page of token ids that the given token id is on
+pub fn token_page(token_id: &TokenId) -> Nat {
+    token_id.clone() / TOKENS_PER_PAGE
+}
+
+/// Hash of the body followed by the hash of each chunk when the body is streamed
+pub fn body_hashes(body: &[u8]) -> (Hash, Vec<Hash>) {
+    let chunks = if body.len() > MAX_CHUNK_BYTES {
+        body.chunks(MAX_CHUNK_BYTES).map(|chunk| sha2::Sha256::digest(chunk).into()).collect()
+    } else {
+        vec![]
+    };
+    (sha2::Sha256::digest(body).into(), chunks)
+}
+
+/// Key of a chunk hash in the hash tree, query strings are never part
+/// of a certified path so chunk keys can't collide with other paths.
+pub fn chunk_key(path: &str, index: usize) -> String {
+    format!("{}?chunk={}", path, index)
+}
+
+/// Upper bound of the chunk keys of a path, ':' sorts after every digit
+pub fn chunk_keys_end(path: &str) -> String {
+    format!("{}?chunk=:", path)
+}
+
+fn nat(nat: &Nat) -> Json {
+    Json::String(nat.0.to_string())
+}
+
+fn int(int: &Int) -> Json {
+    Json::String(int.0.to_string())
+}
+
+fn value(value: &Value) -> Json {
+    match value {
+        Value::Nat(value) => json!({ "Nat": nat(value) }),
+        Value::Int(value) => json!({ "Int": int(value) }),
+        Value::Text(value) => json!({ "Text": value }),
+        Value::Blob(value) => json!({ "Blob": base64::encode(value) }),
+        Value::Array(values) => json!({ "Array": values.iter().map(self::value).collect::<Vec<_>>() }),
+        Value::Map(values) => json!({ "Map": self::values(values) }),
+    }
+}
+
+fn values(values: &HashMap<String, Value>) -> Json {
+    Json::Object(values.iter().map(|(key, val)| (key.clone(), value(val))).collect::<Map<_, _>>())
+}
+
+pub fn text(text: &str) -> Vec<u8> {
+    serde_json::to_vec(text).unwrap()
+}
+
+pub fn nats<'a>(nats: impl Iterator<Item=&'a Nat>) -> Vec<u8> {
+    serde_json::to_vec(&nats.map(nat).collect::<Vec<_>>()).unwrap()
+}
+
+pub fn collection(name: &str, symbol: &str, total_supply: usize) -> Vec<u8> {
+    serde_json::to_vec(&json!({
+        "name": name,
+        "symbol": symbol,
+        "total_supply": total_supply.to_string(),
+    })).unwrap()
+}
+
+pub fn token(token_id: &TokenId, token: &Token) -> Vec<u8> {
+    let mut approved: Vec<&Principal> = token.approved.iter().collect();
+    approved.sort();
+    serde_json::to_vec(&json!({
+        "id": nat(token_id),
+        "account": token.account.to_string(),
+        "tx_id": nat(&token.tx_id),
+        "approved": approved.iter().map(|principal| principal.to_text()).collect::<Vec<_>>(),
+        "metadata": values(&token.metadata),
+    })).unwrap()
+}
+
+pub fn block(block: &[Event]) -> Vec<u8> {
+    serde_json::to_vec(&block.iter().map(|event| json!({
+        "caller": event.caller.to_text(),
+        "operation": event.operation,
+        "time": event.time.to_string(),
+        "details": values(&event.details),
+    })).collect::<Vec<_>>()).unwrap()
+}
+
+/// Certificate header that proves the (absence of the) response body hash in the certified data
+pub fn certificate_header(certificate: &[u8], tree: HashTree) -> HeaderField {
+    (
+        "IC-Certificate".into(),
+        format!("certificate=:{}:, tree=:{}:", base64::encode(certificate), base64::encode(hash::encode_tree(&tree))),
+    )
+}
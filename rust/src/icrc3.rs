@@ -0,0 +1,103 @@
+//! ICRC-3 view of the SLD-3 transaction log, so that explorers and indexers
+//! that follow ICRC-3 can read and verify the log without custom code.
+//!
+//! Each transaction is a block in ICRC-3, its operation is the block type and the caller
+//! together with the details are the `tx` map. The parent hash is at the top of the block.
+use std::collections::HashMap;
+
+use candid::parser::types::FuncMode;
+use candid::types::{Function, Serializer, Type};
+use candid::{Func, Nat};
+use ic_cdk::export::candid::CandidType;
+use ic_cdk::export::Principal;
+use serde::Deserialize;
+
+use crate::hash::PARENT_HASH_KEY;
+use crate::types::{Event, Value};
+
+/// Transaction as ICRC-3 block `{ phash, btype: operation, ts: time, tx: { caller, ..details } }`,
+/// the hash of a block doesn't depend on the order of its map entries.
+pub fn block(event: &Event) -> Value {
+    let mut tx: HashMap<String, Value> = event.details
+        .iter()
+        .filter(|(key, _)| key.as_str() != PARENT_HASH_KEY)
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    tx.insert("caller".into(), Value::Blob(event.caller.as_slice().to_vec()));
+
+    let mut block = HashMap::from([
+        ("btype".to_string(), Value::Text(event.operation.clone())),
+        ("ts".to_string(), Value::Nat(Nat::from(event.time))),
+        ("tx".to_string(), Value::Map(tx)),
+    ]);
+    if let Some(phash) = event.details.get(PARENT_HASH_KEY) {
+        block.insert(PARENT_HASH_KEY.into(), phash.clone());
+    }
+    Value::Map(block)
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct BlockRange {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: Value,
+}
+
+/// Blocks that have been offloaded, to be fetched with `icrc3_get_blocks` from the bucket
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ArchivedBlocks {
+    pub args: Vec<BlockRange>,
+    pub callback: GetBlocksCallback,
+}
+
+/// Query method of the bucket that returns the archived blocks, typed so that
+/// the exported interface tells clients which arguments the callback expects.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(transparent)]
+pub struct GetBlocksCallback(pub Func);
+
+impl CandidType for GetBlocksCallback {
+    fn _ty() -> Type {
+        Type::Func(Function {
+            modes: vec![FuncMode::Query],
+            args: vec![Vec::<BlockRange>::ty()],
+            rets: vec![GetBlocksResult::ty()],
+        })
+    }
+
+    fn idl_serialize<S: Serializer>(&self, serializer: S) -> Result<(), S::Error> {
+        self.0.idl_serialize(serializer)
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct GetArchivesArgs {
+    /// Last archive that has been seen, only archives after it are returned
+    pub from: Option<Principal>,
+}
+
+/// Bucket and the (inclusive) range of blocks that it holds
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ArchiveInfo {
+    pub canister_id: Principal,
+    pub start: Nat,
+    pub end: Nat,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SupportedBlockType {
+    pub block_type: String,
+    pub url: String,
+}
@@ -0,0 +1,88 @@
+//! This module contains the [Memory] that sealed blocks and state snapshots are written to,
+//! stable memory within a canister and a plain byte vector outside of it.
+use std::cell::RefCell;
+
+use ic_cdk::api::stable::{stable64_grow, stable64_read, stable64_size, stable64_write, StableMemoryError};
+
+/// Memory is allocated in pages of 64KiB
+pub const PAGE_BYTES: u64 = 1 << 16;
+
+pub trait Memory {
+    /// Current size in pages
+    fn size(&self) -> u64;
+
+    /// Grow by the given number of pages, returns the previous size in pages
+    fn grow(&self, pages: u64) -> Result<u64, StableMemoryError>;
+
+    /// Read bytes at the given offset, the bytes should be within the current size
+    fn read(&self, offset: u64, buf: &mut [u8]);
+
+    /// Write bytes at the given offset, the bytes should be within the current size
+    fn write(&self, offset: u64, buf: &[u8]);
+}
+
+/// Stable memory of the canister from the start page onwards, the pages before
+/// it are left to the canister that embeds the ledger. Offsets are relative to the start page.
+#[derive(Default)]
+pub struct StableMemory {
+    pub start_page: u64,
+}
+
+impl StableMemory {
+    pub fn new(start_page: u64) -> Self {
+        StableMemory { start_page }
+    }
+
+    fn start(&self) -> u64 {
+        self.start_page * PAGE_BYTES
+    }
+}
+
+impl Memory for StableMemory {
+    fn size(&self) -> u64 {
+        stable64_size().saturating_sub(self.start_page)
+    }
+
+    /// Stable memory is first grown up to the start page if it's still smaller than that
+    fn grow(&self, pages: u64) -> Result<u64, StableMemoryError> {
+        let size = self.size();
+        stable64_grow(self.start_page.saturating_sub(stable64_size()) + pages)?;
+        Ok(size)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        stable64_read(self.start() + offset, buf)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) {
+        stable64_write(self.start() + offset, buf)
+    }
+}
+
+/// Memory that lives on the heap, grows like stable memory does
+#[derive(Default)]
+pub struct VecMemory(pub RefCell<Vec<u8>>);
+
+impl Memory for VecMemory {
+    fn size(&self) -> u64 {
+        self.0.borrow().len() as u64 / PAGE_BYTES
+    }
+
+    fn grow(&self, pages: u64) -> Result<u64, StableMemoryError> {
+        let size = self.size();
+        let mut bytes = self.0.borrow_mut();
+        let len = (size + pages).checked_mul(PAGE_BYTES).ok_or(StableMemoryError::OutOfMemory)?;
+        bytes.resize(len as usize, 0);
+        Ok(size)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        let offset = offset as usize;
+        buf.copy_from_slice(&self.0.borrow()[offset..offset + buf.len()]);
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) {
+        let offset = offset as usize;
+        self.0.borrow_mut()[offset..offset + buf.len()].copy_from_slice(buf);
+    }
+}
@@ -1,33 +1,42 @@
 use std::io;
-use ic_cdk::api::stable::{stable_grow, stable_read, stable_size, stable_write, StableMemoryError};
+use ic_cdk::api::stable::StableMemoryError;
+
+use crate::memory::{Memory, PAGE_BYTES};
+
+/// Stable memory starts with a header that holds the offset of the state snapshot.
+pub const HEADER_BYTES: usize = 8;
+
+/// Saved bytes are prefixed with their length.
+const LENGTH_BYTES: usize = 8;
 
 /// A writer to the stable memory.
 ///
 /// Will attempt to grow the memory as it writes,
 /// and keep offsets and total capacity.
-pub struct StableWriter {
+pub struct StableWriter<'a> {
+    /// The memory that is written to.
+    memory: &'a dyn Memory,
+
     /// The offset of the next write.
     pub offset: usize,
 
     /// The capacity, in pages.
-    pub capacity: u32,
+    pub capacity: u64,
 }
 
-impl Default for StableWriter {
-    fn default() -> Self {
-        let capacity = stable_size();
-
+impl<'a> StableWriter<'a> {
+    /// Writer that starts writing at the given offset.
+    pub fn new(memory: &'a dyn Memory, offset: usize) -> Self {
         Self {
-            offset: 0,
-            capacity,
+            memory,
+            offset,
+            capacity: memory.size(),
         }
     }
-}
 
-impl StableWriter {
     /// Attempts to grow the memory by adding new pages.
-    pub fn grow(&mut self, added_pages: u32) -> Result<(), StableMemoryError> {
-        let old_page_count = stable_grow(added_pages)?;
+    pub fn grow(&mut self, added_pages: u64) -> Result<(), StableMemoryError> {
+        let old_page_count = self.memory.grow(added_pages)?;
         self.capacity = old_page_count + added_pages;
         Ok(())
     }
@@ -37,17 +46,18 @@ impl StableWriter {
     /// The only condition where this will
     /// error out is if it cannot grow the memory.
     pub fn write(&mut self, buf: &[u8]) -> Result<usize, StableMemoryError> {
-        if self.offset + buf.len() > ((self.capacity as usize) << 16) {
-            self.grow((self.offset + buf.len() >> 16) as u32 + 1)?;
+        let needed_pages = ((self.offset + buf.len()) as u64).div_ceil(PAGE_BYTES);
+        if needed_pages > self.capacity {
+            self.grow(needed_pages - self.capacity)?;
         }
 
-        stable_write(self.offset as u32, buf);
+        self.memory.write(self.offset as u64, buf);
         self.offset += buf.len();
         Ok(buf.len())
     }
 }
 
-impl io::Write for StableWriter {
+impl<'a> io::Write for StableWriter<'a> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
         self.write(buf)
             .map_err(|e| io::Error::new(io::ErrorKind::OutOfMemory, e))
@@ -62,26 +72,28 @@ impl io::Write for StableWriter {
 /// A reader to the stable memory.
 ///
 /// Keeps an offset and reads off stable memory consecutively.
-pub struct StableReader {
+pub struct StableReader<'a> {
+    /// The memory that is read from.
+    memory: &'a dyn Memory,
     /// The offset of the next read.
     pub offset: usize,
     /// The capacity, in pages.
-    pub capacity: u32,
+    pub capacity: u64,
 }
 
-impl Default for StableReader {
-    fn default() -> Self {
+impl<'a> StableReader<'a> {
+    /// Reader that starts reading at the given offset.
+    pub fn new(memory: &'a dyn Memory, offset: usize) -> Self {
         Self {
-            offset: 0,
-            capacity: stable_size(),
+            memory,
+            offset,
+            capacity: memory.size(),
         }
     }
-}
 
-impl StableReader {
     /// Reads data from the stable memory location specified by an offset.
     pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, StableMemoryError> {
-        let cap = (self.capacity as usize) << 16;
+        let cap = (self.capacity * PAGE_BYTES) as usize;
         let read_buf = if buf.len() + self.offset > cap {
             if self.offset < cap {
                 &mut buf[..cap - self.offset]
@@ -91,46 +103,52 @@ impl StableReader {
         } else {
             buf
         };
-        stable_read(self.offset as u32, read_buf);
+        self.memory.read(self.offset as u64, read_buf);
         self.offset += read_buf.len();
         Ok(read_buf.len())
     }
 }
 
-impl io::Read for StableReader {
+impl<'a> io::Read for StableReader<'a> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
         self.read(buf).or(Ok(0)) // Read defines EOF to be success
     }
 }
 
-pub fn stable_save<T>(t: T, offset: usize) -> Result<(), candid::Error>
+/// Write the arguments at the given offset, prefixed with their length so that
+/// only the bytes that have been written are read back on restore.
+pub fn stable_save<T>(memory: &dyn Memory, t: T, offset: usize) -> Result<(), candid::Error>
     where
         T: candid::utils::ArgumentEncoder,
 {
-    let mut writer = StableWriter {
-        offset,
-        capacity: stable_size(),
-    };
-    candid::write_args(&mut writer, t)
+    let start = offset + LENGTH_BYTES;
+    let mut writer = StableWriter::new(memory, start);
+    candid::write_args(&mut writer, t)?;
+    let length = writer.offset - start;
+    StableWriter::new(memory, offset).write(&(length as u64).to_le_bytes())
+        .map_err(|err| candid::Error::msg(format!("{:?}", err)))?;
+    Ok(())
 }
 
-pub fn stable_bytes(offset: usize) -> Vec<u8> {
-    let size = (stable_size() as usize) << 16;
-    let mut vec = Vec::with_capacity(size - offset);
-    unsafe {
-        vec.set_len(size - offset);
+/// Bytes that have been written by `stable_save` at the given offset
+pub fn stable_bytes(memory: &dyn Memory, offset: usize) -> Result<Vec<u8>, StableMemoryError> {
+    let mut reader = StableReader::new(memory, offset);
+    let mut length = [0u8; LENGTH_BYTES];
+    if reader.read(&mut length)? != LENGTH_BYTES {
+        return Err(StableMemoryError::OutOfBounds);
     }
-
-    stable_read(offset as u32, vec.as_mut_slice());
-
-    vec
+    let mut vec = vec![0u8; u64::from_le_bytes(length) as usize];
+    if reader.read(&mut vec)? != vec.len() {
+        return Err(StableMemoryError::OutOfBounds);
+    }
+    Ok(vec)
 }
 
-pub fn stable_restore<T>(offset: usize) -> Result<T, String>
+pub fn stable_restore<T>(memory: &dyn Memory, offset: usize) -> Result<T, String>
     where
         T: for<'de> candid::utils::ArgumentDecoder<'de>,
 {
-    let bytes = stable_bytes(offset);
+    let bytes = stable_bytes(memory, offset).map_err(|e| format!("{:?}", e))?;
 
     let mut de =
         candid::de::IDLDeserialize::new(bytes.as_slice()).map_err(|e| format!("{:?}", e))?;
@@ -138,3 +156,56 @@ pub fn stable_restore<T>(offset: usize) -> Result<T, String>
     Ok(res)
 }
 
+/// Write the offset of the state snapshot into the stable memory header
+pub fn stable_save_header(memory: &dyn Memory, state_offset: usize) -> Result<(), StableMemoryError> {
+    let mut writer = StableWriter::new(memory, 0);
+    writer.write(&(state_offset as u64).to_le_bytes())?;
+    Ok(())
+}
+
+/// Read the offset of the state snapshot from the stable memory header
+pub fn stable_restore_header(memory: &dyn Memory) -> Result<usize, StableMemoryError> {
+    let mut reader = StableReader::new(memory, 0);
+    let mut buf = [0u8; HEADER_BYTES];
+    if reader.read(&mut buf)? != HEADER_BYTES {
+        return Err(StableMemoryError::OutOfBounds);
+    }
+    Ok(u64::from_le_bytes(buf) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::VecMemory;
+
+    use super::*;
+
+    #[test]
+    fn write_grows_by_missing_pages() {
+        let memory = VecMemory::default();
+        let mut writer = StableWriter::new(&memory, 0);
+        writer.write(&[1; 10]).unwrap();
+        assert_eq!((writer.capacity, memory.size()), (1, 1));
+
+        // Writes up to the end of the last page don't grow the memory
+        writer.write(&vec![2; PAGE_BYTES as usize - 10]).unwrap();
+        assert_eq!(memory.size(), 1);
+
+        writer.write(&vec![3; 2 * PAGE_BYTES as usize + 1]).unwrap();
+        assert_eq!((writer.capacity, memory.size()), (4, 4));
+
+        let mut reader = StableReader::new(&memory, 0);
+        let mut buf = vec![0; 3 * PAGE_BYTES as usize + 2];
+        reader.read(&mut buf).unwrap();
+        assert_eq!((buf[9], buf[10], buf[PAGE_BYTES as usize], buf[3 * PAGE_BYTES as usize + 1]), (1, 2, 3, 0));
+    }
+
+    #[test]
+    fn restore_reads_saved_bytes() {
+        let memory = VecMemory::default();
+        stable_save_header(&memory, HEADER_BYTES).unwrap();
+        stable_save(&memory, ("saved", 42u64), HEADER_BYTES).unwrap();
+        let offset = stable_restore_header(&memory).unwrap();
+        assert_eq!(stable_bytes(&memory, offset).unwrap(), candid::encode_args(("saved", 42u64)).unwrap());
+        assert_eq!(stable_restore::<(String, u64)>(&memory, offset).unwrap(), ("saved".to_string(), 42));
+    }
+}
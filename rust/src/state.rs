@@ -1,122 +1,705 @@
-use std::collections::{HashMap, HashSet};
-use std::convert::TryFrom;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::iter::Peekable;
+use std::ops::Bound;
 
-use candid::Nat;
-use ic_cdk::{caller, id, print, trap};
-use ic_cdk::api::{canister_balance, data_certificate, set_certified_data, time};
-use ic_cdk::api::stable::stable_size;
+use candid::{Func, Nat};
+use ic_cdk::trap;
 use ic_cdk::export::candid::CandidType;
 use ic_cdk::export::Principal;
-use ic_certified_map::{AsHashTree, Hash, labeled, labeled_hash, RbTree};
+use ic_certified_map::{AsHashTree, fork, Hash, HashTree, labeled, labeled_hash, RbTree};
 use num_traits::ToPrimitive;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use serde_bytes::ByteBuf;
 use sha2::Digest;
 
-use crate::{ApproveArgs, ApproveError, HistoryEntry, HttpRequest, HttpResponse, SetCustodianArgs, SetCustodiansError, StableReader, StableWriter, TransferArgs, TransferFromArgs, TransferFromError};
+use crate::env::{CanisterEnvironment, Environment};
+use crate::hash;
+use crate::http;
+#[cfg(feature = "sld3")]
+use crate::icrc3::{self, ArchivedBlocks, ArchiveInfo, BlockRange, BlockWithId, GetBlocksCallback, GetBlocksResult};
 use crate::rc_bytes::RcBytes;
-use crate::types::{Account, BlockOrBucket, Event, EventOrBucket, GenericError, HISTORY_ENTRY_BYTES, Offset, StableBytes, SupportedStandard, Time, Token, TokenId, Value};
+use crate::stable::{HEADER_BYTES, stable_restore, stable_restore_header, stable_save, stable_save_header, StableReader, StableWriter};
+use crate::tx::TxKind;
+use crate::types::{Account, BlockOrBucket, Event, GenericError, HttpRequest, HttpResponse, InitArgs, Royalty, SetCustodianArgs, SetCustodiansError, SetMetadataError, StreamingCallback, StreamingCallbackHttpResponse, StreamingCallbackToken, StreamingStrategy, Token, TokenId, TransferError, TransferFromArgs, TransferFromError, TxError, UpgradeArgs, Value};
+#[cfg(feature = "sld2")]
+use crate::types::{ApproveArgs, ApproveError, SetApprovalArgs, SetApprovalError, SetApprovalForAllArgs, SetApprovalForAllError};
+#[cfg(feature = "sld3")]
+use crate::types::{DataCertificate, EventOrBucket, OffloadBlocksError};
+#[cfg(feature = "sld4")]
+use crate::types::{MintArgs, MintError};
+#[cfg(feature = "sld5")]
+use crate::types::{BurnArgs, BurnError};
+#[cfg(feature = "sld6")]
+use crate::types::SetMetadataArgs;
+#[cfg(feature = "sld7")]
+use crate::types::RoyaltyFee;
+#[cfg(feature = "sld8")]
+use crate::types::{SetRoyaltyFeeArgs, SetRoyaltyFeeError};
 
-// pub const HISTORY_PAGE_SIZE: usize = 5;
+/// Number of token ids returned per page when no page size has been configured
+pub const DEFAULT_PAGE_SIZE: usize = 100_000;
+
+/// Max Candid encoded size of token ids returned per page, stays
+/// below the 2MiB reply size limit including any encoding overhead.
+pub const MAX_PAGE_BYTES: usize = 2_000_000;
+
+/// Transactions with a created at time older than the window are rejected, within
+/// the window transactions are deduplicated, defaults to 24 hours as in ICRC-1.
+pub const DEFAULT_TX_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Allowed difference between the created at time and the canister time, defaults to 2 minutes.
+pub const DEFAULT_PERMITTED_DRIFT: u64 = 2 * 60 * 1_000_000_000;
+
+/// Max number of ICRC-3 blocks returned per request, blocks
+/// are also limited by their size to stay below the reply size limit.
+#[cfg(feature = "sld3")]
+pub const MAX_BLOCKS_PER_REQUEST: usize = 2_000;
+
+/// Royalty fees are in basis points, so a fee can be at most 100%
+#[cfg(feature = "sld7")]
+pub const MAX_ROYALTY_FEE: u16 = 10_000;
+
+/// Number of transactions in a block when no block size has been configured
+pub const DEFAULT_BLOCK_SIZE: usize = 1_000;
+
+/// Number of approvals per token and operators per account when no max has been configured
+pub const DEFAULT_MAX_APPROVALS: usize = 256;
+
+/// Number of custodians when no max has been configured
+pub const DEFAULT_MAX_CUSTODIANS: usize = 256;
 
-#[derive(Default)]
 pub struct State {
     pub metadata: HashMap<String, Value>,
     pub name: String,
     pub symbol: String,
-    pub tokens: HashMap<TokenId, Token>,
+    pub tokens: BTreeMap<TokenId, Token>,
+    /// Token ids owned by each account, derived from tokens
+    pub owners: HashMap<Account, BTreeSet<TokenId>>,
+    /// Operators that are approved to transfer any token of an account
+    #[cfg(feature = "sld2")]
+    pub operators: HashMap<Account, HashSet<Principal>>,
+    /// Collection royalty, tokens can override it with their own royalty
+    #[cfg(feature = "sld7")]
+    pub royalty: Option<Royalty>,
+    /// Number of tokens that are not owned by the minter account
+    pub supply: usize,
     pub current_block: Vec<Event>,
+    /// Stable memory offset at which each sealed block ends
     pub block_indexes: Vec<usize>,
+    /// Hashes of the http body of each sealed block, so that sealed
+    /// blocks don't have to be read again to rebuild the hash tree
+    pub history_hashes: Vec<(Hash, Vec<Hash>)>,
+    pub block_size: usize,
+    /// Buckets that sealed blocks have been offloaded to, each bucket
+    /// holds the blocks up to (exclusive) the given block id
+    pub buckets: Vec<(Principal, usize)>,
+    pub offloading: bool,
     pub tx_total: Nat,
+    /// Hash of the last transaction, each next transaction carries it as parent hash
+    pub tip_hash: Option<Hash>,
+    pub tx_window: u64,
+    pub permitted_drift: u64,
+    /// Transaction ids by created at time and transaction hash, used for deduplication
+    pub tx_hashes: BTreeMap<(u64, Hash), Nat>,
     pub custodians_tx: Nat,
     pub custodians: HashSet<Principal>,
+    pub metadata_tx: Nat,
+    /// Metadata keys that can no longer be changed
+    pub frozen_metadata: HashSet<String>,
+    /// Number of tokens that can be minted, burned tokens still count towards the max
+    pub max_supply: Option<usize>,
+    pub max_approvals: usize,
+    pub max_custodians: usize,
+    pub page_size: usize,
     pub hash_tree: RbTree<String, Hash>,
+    /// Environment the state runs in, never persisted
+    pub env: Box<dyn Environment>,
 }
 
-// #[derive(CandidType, Clone, Default, Deserialize)]
-// pub struct StableState {
-//     pub name: String,
-//     pub symbol: String,
-//     // pub tokens: Vec<Token>,
-//     pub approved: HashMap<Principal, Vec<Principal>>,
-//     pub extensions: HashMap<Principal, Vec<String>>,
-//     pub custodians: Vec<Principal>,
-//     pub offset_hashes: HashMap<u32, Hash>,
-//     pub history_hash: Option<Hash>,
-// }
+impl Default for State {
+    fn default() -> Self {
+        State::with_env(Box::new(CanisterEnvironment::default()))
+    }
+}
 
 impl State {
-    pub fn init(&mut self, name: String, symbol: String, custodian: Principal) {
-        self.name = name;
-        self.symbol = symbol;
-        self.custodians = HashSet::from([custodian]);
-
-        // Call custodian setter so history entry is written, setter will not an throw error
-        // since custodian has already been added to the approved list above
-        self.set_custodian(SetCustodianArgs {
-            custodian,
-            approved: true,
-        }).unwrap();
+    pub fn with_env(env: Box<dyn Environment>) -> Self {
+        State {
+            metadata: HashMap::default(),
+            name: String::default(),
+            symbol: String::default(),
+            tokens: BTreeMap::default(),
+            owners: HashMap::default(),
+            #[cfg(feature = "sld2")]
+            operators: HashMap::default(),
+            #[cfg(feature = "sld7")]
+            royalty: None,
+            supply: 0,
+            current_block: Vec::default(),
+            block_indexes: Vec::default(),
+            history_hashes: Vec::default(),
+            block_size: DEFAULT_BLOCK_SIZE,
+            buckets: Vec::default(),
+            offloading: false,
+            tx_total: Nat::default(),
+            tip_hash: None,
+            tx_window: DEFAULT_TX_WINDOW,
+            permitted_drift: DEFAULT_PERMITTED_DRIFT,
+            tx_hashes: BTreeMap::default(),
+            custodians_tx: Nat::default(),
+            custodians: HashSet::default(),
+            metadata_tx: Nat::default(),
+            frozen_metadata: HashSet::default(),
+            max_supply: None,
+            max_approvals: DEFAULT_MAX_APPROVALS,
+            max_custodians: DEFAULT_MAX_CUSTODIANS,
+            page_size: DEFAULT_PAGE_SIZE,
+            hash_tree: RbTree::default(),
+            env,
+        }
+    }
+
+    /// Restore the state from a snapshot, derived data is rebuilt within the given environment
+    pub fn from_stable(stable_state: StableState, env: Box<dyn Environment>) -> Self {
+        match stable_state {
+            StableState::V1(stable_state) => {
+                let mut state = State {
+                    metadata: stable_state.metadata,
+                    name: stable_state.name,
+                    symbol: stable_state.symbol,
+                    tokens: stable_state.tokens,
+                    owners: HashMap::default(),
+                    #[cfg(feature = "sld2")]
+                    operators: stable_state.operators,
+                    #[cfg(feature = "sld7")]
+                    royalty: stable_state.royalty,
+                    supply: 0,
+                    current_block: stable_state.current_block,
+                    block_indexes: stable_state.block_indexes,
+                    history_hashes: stable_state.history_hashes,
+                    block_size: stable_state.block_size,
+                    buckets: stable_state.buckets,
+                    offloading: false,
+                    tx_total: stable_state.tx_total,
+                    tip_hash: stable_state.tip_hash,
+                    tx_window: stable_state.tx_window,
+                    permitted_drift: stable_state.permitted_drift,
+                    tx_hashes: stable_state.tx_hashes,
+                    custodians_tx: stable_state.custodians_tx,
+                    custodians: stable_state.custodians,
+                    metadata_tx: stable_state.metadata_tx,
+                    frozen_metadata: stable_state.frozen_metadata,
+                    max_supply: stable_state.max_supply,
+                    max_approvals: stable_state.max_approvals,
+                    max_custodians: stable_state.max_custodians,
+                    page_size: stable_state.page_size,
+                    hash_tree: RbTree::default(),
+                    env,
+                };
+                state.rebuild_owners();
+                state.rebuild_hash_tree();
+                state
+            }
+        }
+    }
+
+    /// Snapshot of the state together with the environment it ran in
+    pub fn into_stable(self) -> (StableState, Box<dyn Environment>) {
+        let stable_state = StableState::V1(StableStateV1 {
+            metadata: self.metadata,
+            name: self.name,
+            symbol: self.symbol,
+            tokens: self.tokens,
+            #[cfg(feature = "sld2")]
+            operators: self.operators,
+            #[cfg(not(feature = "sld2"))]
+            operators: HashMap::default(),
+            #[cfg(feature = "sld7")]
+            royalty: self.royalty,
+            #[cfg(not(feature = "sld7"))]
+            royalty: None,
+            current_block: self.current_block,
+            block_indexes: self.block_indexes,
+            history_hashes: self.history_hashes,
+            block_size: self.block_size,
+            buckets: self.buckets,
+            tx_total: self.tx_total,
+            tip_hash: self.tip_hash,
+            tx_window: self.tx_window,
+            permitted_drift: self.permitted_drift,
+            tx_hashes: self.tx_hashes,
+            custodians_tx: self.custodians_tx,
+            custodians: self.custodians,
+            metadata_tx: self.metadata_tx,
+            frozen_metadata: self.frozen_metadata,
+            max_supply: self.max_supply,
+            max_approvals: self.max_approvals,
+            max_custodians: self.max_custodians,
+            page_size: self.page_size,
+        });
+        (stable_state, self.env)
+    }
+
+    /// Write the snapshot after the sealed blocks in memory, the header points to where it starts
+    pub fn save(self) -> Result<(), String> {
+        let offset = self.stable_offset();
+        let (stable_state, env) = self.into_stable();
+        stable_save_header(env.memory(), offset).map_err(|err| format!("{:?}", err))?;
+        stable_save(env.memory(), (stable_state, ), offset).map_err(|err| format!("{:?}", err))
+    }
+
+    /// Read the snapshot that the header points to from the memory of the given environment,
+    /// memory without a snapshot (e.g. of a ledger that didn't save one yet) gives a new state.
+    pub fn restore(env: Box<dyn Environment>) -> Result<Self, String> {
+        let offset = match env.memory().size() {
+            0 => 0,
+            _ => stable_restore_header(env.memory()).map_err(|err| format!("{:?}", err))?
+        };
+        // Snapshots are always written after the header
+        if offset < HEADER_BYTES {
+            let mut state = State::with_env(env);
+            state.rebuild_hash_tree();
+            return Ok(state);
+        }
+        let (stable_state, ) = stable_restore::<(StableState, )>(env.memory(), offset)?;
+        Ok(State::from_stable(stable_state, env))
+    }
+}
+
+/// Versioned snapshot of the state that is kept in stable memory during upgrades,
+/// the hash tree is not part of the snapshot since it's rebuilt from the state itself.
+#[derive(CandidType, Deserialize)]
+pub enum StableState {
+    V1(StableStateV1),
+}
+
+/// Fields of standards that are not enabled are still part of the snapshot,
+/// so that a snapshot can always be restored whatever features are enabled.
+#[derive(CandidType, Deserialize)]
+pub struct StableStateV1 {
+    pub metadata: HashMap<String, Value>,
+    pub name: String,
+    pub symbol: String,
+    pub tokens: BTreeMap<TokenId, Token>,
+    pub operators: HashMap<Account, HashSet<Principal>>,
+    pub royalty: Option<Royalty>,
+    pub current_block: Vec<Event>,
+    pub block_indexes: Vec<usize>,
+    pub history_hashes: Vec<(Hash, Vec<Hash>)>,
+    pub block_size: usize,
+    pub buckets: Vec<(Principal, usize)>,
+    pub tx_total: Nat,
+    pub tip_hash: Option<Hash>,
+    pub tx_window: u64,
+    pub permitted_drift: u64,
+    pub tx_hashes: BTreeMap<(u64, Hash), Nat>,
+    pub custodians_tx: Nat,
+    pub custodians: HashSet<Principal>,
+    pub metadata_tx: Nat,
+    pub frozen_metadata: HashSet<String>,
+    pub max_supply: Option<usize>,
+    pub max_approvals: usize,
+    pub max_custodians: usize,
+    pub page_size: usize,
+}
+
+impl From<State> for StableState {
+    fn from(state: State) -> Self {
+        state.into_stable().0
+    }
+}
+
+impl From<StableState> for State {
+    fn from(stable_state: StableState) -> Self {
+        State::from_stable(stable_state, Box::new(CanisterEnvironment::default()))
+    }
+}
+
+impl State {
+    pub fn init(&mut self, args: InitArgs) -> Result<(), String> {
+        if let Some(block_size) = &args.block_size {
+            self.block_size = positive(block_size, "Block size")?;
+        }
+        self.upgrade(UpgradeArgs {
+            max_supply: args.max_supply,
+            max_approvals: args.max_approvals,
+            max_custodians: args.max_custodians,
+            page_size: args.page_size,
+            tx_window: args.tx_window,
+            permitted_drift: args.permitted_drift,
+        })?;
+        let custodians: HashSet<Principal> = args.custodians.into_iter().collect();
+        if custodians.is_empty() {
+            return Err("At least one custodian is required".into());
+        }
+        if custodians.len() > self.max_custodians {
+            return Err(format!("Custodians should be at most {}", self.max_custodians));
+        }
+        for (key, value) in &args.metadata {
+            validate_metadata(key, value).map_err(|_| format!("Metadata {} is invalid", key))?;
+        }
+
+        // Standard metadata entries are kept in sync with name, symbol and logo
+        self.metadata = args.metadata;
+        self.metadata.insert("sld1:name".into(), Value::Text(args.name.clone()));
+        self.metadata.insert("sld1:symbol".into(), Value::Text(args.symbol.clone()));
+        if let Some(logo) = args.logo {
+            self.metadata.insert("sld1:logo".into(), Value::Text(logo));
+        }
+        self.name = args.name;
+        self.symbol = args.symbol;
+
+        // Write history entry for each custodian, the caller that installs
+        // the canister doesn't necessarily have to be a custodian itself.
+        let caller = self.env.caller();
+        for custodian in custodians {
+            self.custodians.insert(custodian);
+            self.write_custodian_tx(caller, custodian, true);
+        }
+
+        // Set initial http certified data, the history has already been certified above
+        self.certify("/".into());
+        self.certify("/name".into());
+        self.certify("/symbol".into());
+        self.certify("/total_supply".into());
+        self.certify("/tokens".into());
+        Ok(())
+    }
+
+    /// Change the limits that are given, limits are validated against the current state
+    pub fn upgrade(&mut self, args: UpgradeArgs) -> Result<(), String> {
+        if let Some(max_supply) = &args.max_supply {
+            let max_supply = positive(max_supply, "Max supply")?;
+            if max_supply < self.tokens.len() {
+                return Err(format!("Max supply should be at least the {} tokens that have been minted", self.tokens.len()));
+            }
+            self.max_supply = Some(max_supply);
+        }
+        if let Some(max_approvals) = &args.max_approvals {
+            self.max_approvals = positive(max_approvals, "Max approvals")?;
+        }
+        if let Some(max_custodians) = &args.max_custodians {
+            let max_custodians = positive(max_custodians, "Max custodians")?;
+            if max_custodians < self.custodians.len() {
+                return Err(format!("Max custodians should be at least the {} current custodians", self.custodians.len()));
+            }
+            self.max_custodians = max_custodians;
+        }
+        if let Some(page_size) = &args.page_size {
+            self.page_size = positive(page_size, "Page size")?;
+        }
+        if let Some(tx_window) = args.tx_window {
+            self.tx_window = tx_window;
+        }
+        if let Some(permitted_drift) = args.permitted_drift {
+            self.permitted_drift = permitted_drift;
+        }
+        Ok(())
+    }
+
+    /// Minter account is equal to canister principal
+    pub fn minting_account(&self) -> Account {
+        Account::minter(self.env.id())
+    }
+
+    /// Rebuild the owner index and supply from tokens, the index itself is never persisted
+    pub fn rebuild_owners(&mut self) {
+        let minter_account = self.minting_account();
+        self.owners = HashMap::default();
+        for (token_id, token) in &self.tokens {
+            self.owners.entry(token.account).or_default().insert(token_id.clone());
+        }
+        self.supply = self.tokens.len() - self.owners.get(&minter_account).map_or(0, |token_ids| token_ids.len());
+    }
+
+    /// Move token between accounts in the owner index and update the supply accordingly,
+    /// tokens moving from or to the minter account are respectively minted or burned.
+    fn index_token(&mut self, token_id: &TokenId, from: Option<Account>, to: Account) {
+        let minter_account = self.minting_account();
+        if let Some(from) = from {
+            if let Some(token_ids) = self.owners.get_mut(&from) {
+                token_ids.remove(token_id);
+                if token_ids.is_empty() {
+                    self.owners.remove(&from);
+                }
+            }
+        }
+        self.owners.entry(to).or_default().insert(token_id.clone());
+
+        let from_supply = from.is_some_and(|from| from != minter_account);
+        let to_supply = to != minter_account;
+        if !from_supply && to_supply {
+            self.supply += 1;
+        } else if from_supply && !to_supply {
+            self.supply -= 1;
+        }
+        if from_supply != to_supply {
+            self.certify_supply(token_id);
+        }
+    }
+
+    /// Rebuild the hash tree from the state, the tree itself is never persisted
+    pub fn rebuild_hash_tree(&mut self) {
+        self.hash_tree = RbTree::default();
+        let mut paths = vec!["/".to_string(), "/name".into(), "/symbol".into(), "/total_supply".into(), "/tokens".into()];
+        let minter_account = self.minting_account();
+        let mut pages = BTreeSet::new();
+        for (token_id, token) in &self.tokens {
+            paths.push(format!("/token/{}", token_id.0));
+            paths.extend(token.metadata
+                .iter()
+                .filter(|(_, value)| matches!(value, Value::Blob(_)))
+                .map(|(key, _)| format!("/token/{}/{}", token_id.0, key)));
+            if token.account != minter_account {
+                pages.insert(http::token_page(token_id));
+            }
+        }
+        paths.extend(pages.iter().map(|page| format!("/tokens/{}", page.0)));
+        paths.push(format!("/history/{}", self.block_indexes.len()));
+        for path in paths {
+            if let Some((_, body)) = self.http_body(&path) {
+                self.insert_hashes(path, http::body_hashes(&body));
+            }
+        }
+
+        // Sealed blocks don't change, so their hashes are taken as they were when the block was sealed
+        for block_id in self.offloaded_blocks()..self.block_indexes.len() {
+            let hashes = self.history_hashes[block_id].clone();
+            self.insert_hashes(format!("/history/{}", block_id), hashes);
+        }
+        self.update_certified_data();
+    }
+
+    /// Content type and body that is served by the http interface for the given path
+    fn http_body(&self, path: &str) -> Option<(&'static str, Vec<u8>)> {
+        let mut parts = path.splitn(4, '/').skip(1);
+        match (parts.next()?, parts.next(), parts.next()) {
+            ("", None, _) => Some(("application/json", http::collection(&self.name, &self.symbol, self.supply))),
+            ("name", None, _) => Some(("application/json", http::text(&self.name))),
+            ("symbol", None, _) => Some(("application/json", http::text(&self.symbol))),
+            ("total_supply", None, _) => Some(("application/json", self.supply.to_string().into_bytes())),
+            // Pages that hold at least one token, each page holds the token ids within its range
+            ("tokens", None, _) => {
+                let minter_account = self.minting_account();
+                let pages: BTreeSet<Nat> = self.tokens
+                    .iter()
+                    .filter(|(_, token)| token.account != minter_account)
+                    .map(|(token_id, _)| http::token_page(token_id))
+                    .collect();
+                Some(("application/json", http::nats(pages.iter())))
+            }
+            ("tokens", Some(page), None) => {
+                let minter_account = self.minting_account();
+                let start = page.parse::<Nat>().ok()? * http::TOKENS_PER_PAGE;
+                let end = start.clone() + http::TOKENS_PER_PAGE;
+                let token_ids: Vec<&TokenId> = self.tokens
+                    .range(start..end)
+                    .filter(|(_, token)| token.account != minter_account)
+                    .map(|(token_id, _)| token_id)
+                    .collect();
+                if token_ids.is_empty() {
+                    return None;
+                }
+                Some(("application/json", http::nats(token_ids.into_iter())))
+            }
+            ("token", Some(token_id), key) => {
+                let token_id = token_id.parse::<Nat>().ok()?;
+                let token = self.tokens.get(&token_id)?;
+                match key {
+                    // Blob metadata like token images is served as is
+                    Some(key) => match token.metadata.get(key)? {
+                        Value::Blob(value) => Some(("application/octet-stream", value.clone())),
+                        _ => None
+                    },
+                    None => Some(("application/json", http::token(&token_id, token)))
+                }
+            }
+            ("history", Some(block_id), None) => match self.read_block(block_id.parse::<Nat>().ok()?)? {
+                BlockOrBucket::Block(block) => Some(("application/json", http::block(&block))),
+                BlockOrBucket::Bucket(_) => None
+            },
+            _ => None
+        }
+    }
 
-        // Set initial http certified data
-        // self.hash_tree.insert("/name".into(), sha2::Sha256::digest(format!("\"{}\"", self.name).as_bytes()).into());
-        // self.hash_tree.insert("/symbol".into(), sha2::Sha256::digest(format!("\"{}\"", self.symbol).as_bytes()).into());
-        // self.hash_tree.insert("/total_supply".into(), sha2::Sha256::digest("0".as_bytes()).into());
-        // self.hash_tree.insert("/tokens".into(), sha2::Sha256::digest("[]".as_bytes()).into());
-        // self.hash_tree.insert("/history/0".into(), sha2::Sha256::digest("[]".as_bytes()).into());
-        // self.hash_tree.insert("/history_total".into(), sha2::Sha256::digest("0".as_bytes()).into());
-        // set_certified_data(&labeled_hash(b"http_assets", &self.hash_tree.root_hash()));
+    /// Insert the hash of the body, bodies that are streamed also get the hash of each chunk
+    /// inserted so that every chunk can be verified on its own.
+    fn insert_hashes(&mut self, path: String, (hash, chunks): (Hash, Vec<Hash>)) {
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            self.hash_tree.insert(http::chunk_key(&path, index), chunk);
+        }
+        self.hash_tree.insert(path, hash);
+    }
+
+    /// Update the certified hash of the body at the given path, paths without a body are removed
+    fn certify(&mut self, path: String) {
+        let hashes = self.http_body(&path).map(|(_, body)| http::body_hashes(&body));
+        self.certify_hashes(path, hashes);
+    }
+
+    /// Replace the certified hashes of the given path, paths without hashes are removed
+    fn certify_hashes(&mut self, path: String, hashes: Option<(Hash, Vec<Hash>)>) {
+        let mut index = 0;
+        while self.hash_tree.get(http::chunk_key(&path, index).as_bytes()).is_some() {
+            self.hash_tree.delete(http::chunk_key(&path, index).as_bytes());
+            index += 1;
+        }
+        match hashes {
+            Some(hashes) => self.insert_hashes(path, hashes),
+            None => self.hash_tree.delete(path.as_bytes()),
+        }
+        self.update_certified_data();
+    }
+
+    /// Tree with the index and hash of the last transaction, certified next to the http assets
+    fn tip_tree(&self) -> Option<HashTree<'static>> {
+        let tip_hash = self.tip_hash?;
+        let mut last_block_index = vec![];
+        let last_tx_id: Nat = self.tx_total.clone() - 1;
+        last_tx_id.encode(&mut last_block_index).unwrap();
+        Some(fork(
+            labeled(b"last_block_hash", HashTree::Leaf(Cow::Owned(tip_hash.to_vec()))),
+            labeled(b"last_block_index", HashTree::Leaf(Cow::Owned(last_block_index))),
+        ))
+    }
+
+    /// Certified tree with the given http assets tree, the tip is either revealed or pruned
+    fn certified_tree<'a>(&self, http_assets: HashTree<'a>, reveal_tip: bool) -> HashTree<'a> {
+        match self.tip_tree() {
+            Some(tip) if reveal_tip => fork(http_assets, tip),
+            Some(tip) => fork(http_assets, HashTree::Pruned(tip.reconstruct())),
+            None => http_assets,
+        }
+    }
+
+    fn update_certified_data(&self) {
+        let http_assets = HashTree::Pruned(labeled_hash(b"http_assets", &self.hash_tree.root_hash()));
+        self.env.set_certified_data(&self.certified_tree(http_assets, false).reconstruct());
+    }
+
+    fn certify_token(&mut self, token_id: &TokenId) {
+        self.certify(format!("/token/{}", token_id.0));
+    }
+
+    /// Token metadata does not change after mint, so blobs only have to be certified once
+    #[cfg(feature = "sld4")]
+    fn certify_token_metadata(&mut self, token_id: &TokenId) {
+        let keys: Vec<String> = self.tokens
+            .get(token_id)
+            .map(|token| token.metadata
+                .iter()
+                .filter(|(_, value)| matches!(value, Value::Blob(_)))
+                .map(|(key, _)| key.clone())
+                .collect())
+            .unwrap_or_default();
+        for key in keys {
+            self.certify(format!("/token/{}/{}", token_id.0, key));
+        }
+    }
+
+    /// Certify the supply and the page of the token that has been minted or burned, the
+    /// index of pages only changes when the page has been added or has become empty.
+    fn certify_supply(&mut self, token_id: &TokenId) {
+        self.certify("/".into());
+        self.certify("/total_supply".into());
+        let path = format!("/tokens/{}", http::token_page(token_id).0);
+        let page_existed = self.hash_tree.get(path.as_bytes()).is_some();
+        self.certify(path.clone());
+        if page_existed != self.hash_tree.get(path.as_bytes()).is_some() {
+            self.certify("/tokens".into());
+        }
+    }
+
+    /// Offset in stable memory where the state snapshot is written on upgrade
+    pub fn stable_offset(&self) -> usize {
+        self.block_indexes.last().copied().unwrap_or(HEADER_BYTES)
     }
 
     pub fn total_supply(&self) -> Nat {
-        let minter_account = Account::minter();
-        Nat::from(self.tokens.iter().filter(|(_, token)| token.account != minter_account).count())
+        Nat::from(self.supply)
     }
 
     pub fn balance_of(&self, account: &Account) -> Nat {
-        Nat::from(self.tokens.iter().filter(|(_, token)| token.account == *account).count())
+        Nat::from(self.owners.get(account).map_or(0, |token_ids| token_ids.len()))
     }
 
     pub fn owner_of(&self, token_id: &TokenId) -> Option<Account> {
         self.tokens.get(token_id).map(|token| token.account)
     }
 
+    pub fn metadata_of(&self, token_id: &TokenId) -> Option<&HashMap<String, Value>> {
+        self.tokens.get(token_id).map(|token| &token.metadata)
+    }
+
+    /// Token ids on the given page, pages are cut off at the max page size in bytes like
+    /// `tokens_after` does, so that the reply always fits in a message. Each page starts
+    /// where the previous one stopped, so no token ids are lost between pages.
     pub fn tokens(&self, page: &Nat) -> Vec<&TokenId> {
-        let minter_account = Account::minter();
-        page.0.to_usize().map_or(vec![], |page| self.tokens
-            .iter()
-            .filter(|(_, token)| token.account != minter_account)
-            .skip(page * 100_000)
-            .take(100_000)
-            .map(|(token_id, _)| token_id)
-            .collect())
+        let minter_account = self.minting_account();
+        nth_page(
+            self.tokens
+                .iter()
+                .filter(|(_, token)| token.account != minter_account)
+                .map(|(token_id, _)| token_id),
+            page,
+            self.page_size,
+        )
     }
 
     pub fn tokens_of(&self, account: &Account, page: &Nat) -> Vec<&TokenId> {
-        page.0.to_usize().map_or(vec![], |page| self.tokens
-            .iter()
-            .filter(|(_, token)| token.account == *account)
-            .skip(page * 100_000)
-            .take(100_000)
-            .map(|(token_id, _)| token_id)
-            .collect())
+        self.owners.get(account).map_or(vec![], |token_ids| nth_page(token_ids.iter(), page, self.page_size))
+    }
+
+    /// Token ids in ascending order starting after the given token id, the number of
+    /// token ids is limited by both the given limit and the max page size in bytes.
+    pub fn tokens_after(&self, start_after: Option<&TokenId>, limit: Option<&Nat>) -> Vec<&TokenId> {
+        let minter_account = self.minting_account();
+        let range = (start_after.map_or(Bound::Unbounded, Bound::Excluded), Bound::Unbounded);
+        take_page(
+            &mut self.tokens
+                .range::<TokenId, _>(range)
+                .filter(|(_, token)| token.account != minter_account)
+                .map(|(token_id, _)| token_id)
+                .peekable(),
+            limit,
+            self.page_size,
+        )
     }
 
+    pub fn tokens_of_after(&self, account: &Account, start_after: Option<&TokenId>, limit: Option<&Nat>) -> Vec<&TokenId> {
+        let range = (start_after.map_or(Bound::Unbounded, Bound::Excluded), Bound::Unbounded);
+        self.owners.get(account).map_or(vec![], |token_ids| take_page(
+            &mut token_ids.range::<TokenId, _>(range).peekable(),
+            limit,
+            self.page_size,
+        ))
+    }
+
+    /// Deprecated, replaced by `set_approval` which does not reject the caller as spender
+    #[cfg(feature = "sld2")]
     pub fn approve(&mut self, args: ApproveArgs) -> Result<Nat, ApproveError> {
-        let caller = caller();
-        if args.spender == caller {
+        if args.spender == self.env.caller() {
             return Err(ApproveError::NotSelf);
         }
-        let mut token = self.tokens.get(&args.token_id).map(|token| token.clone()).ok_or(ApproveError::NotFound)?;
+        self.set_approval(args.into()).map_err(ApproveError::from)
+    }
+
+    #[cfg(feature = "sld2")]
+    pub fn set_approval(&mut self, args: SetApprovalArgs) -> Result<Nat, SetApprovalError> {
+        let caller = self.env.caller();
+        let tx_hash = self.check_tx(caller, "sld2:approve", &args, args.created_at_time)?;
+        let mut token = self.tokens.get(&args.token_id).cloned().ok_or(SetApprovalError::NotFound)?;
         let from = Account::new(caller, args.from_subaccount);
         let is_owner = from == token.account;
         if !is_owner {
-            return Err(ApproveError::NotOwner);
+            return Err(SetApprovalError::NotOwner);
         }
         match args.approved {
             true => {
-                if token.approved.len() == 256 {
-                    return Err(ApproveError::MaxApprovals(Nat::from(256)));
+                if token.approved.len() >= self.max_approvals && !token.approved.contains(&args.spender) {
+                    return Err(SetApprovalError::MaxApprovals(Nat::from(self.max_approvals)));
                 }
                 token.approved.insert(args.spender);
             }
@@ -125,56 +708,110 @@ impl State {
             }
         }
 
-        let mut event = Event {
-            caller,
-            operation: "sld2:approve".into(),
-            time: time(),
-            details: HashMap::from([
-                ("token_id".into(), Value::Nat(args.token_id.clone())),
-                ("spender".into(), Value::Text(args.spender.to_string())),
-                ("approved".into(), Value::Nat(Nat::from(if args.approved { 1 } else { 0 }))),
-                ("from_tx".into(), Value::Nat(token.tx_id.clone())),
-            ]),
-        };
-        if let Some(memo) = args.memo {
-            event.details.insert("memo".into(), Value::Blob(Vec::from(memo)));
-        }
-        if let Some(created_at_time) = args.created_at_time {
-            event.details.insert("time".into(), Value::Nat(Nat::from(created_at_time)));
-        }
-        self.write_tx(event);
+        self.write_tx(caller, TxKind::Approve {
+            token_id: args.token_id.clone(),
+            spender: args.spender,
+            approved: args.approved,
+            from_tx: token.tx_id.clone(),
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+        });
         let tx_id: Nat = self.tx_total.clone() - 1;
+        self.record_tx(tx_hash, &tx_id);
         token.tx_id = tx_id.clone();
-        self.tokens.insert(args.token_id, token);
+        self.tokens.insert(args.token_id.clone(), token);
+        self.certify_token(&args.token_id);
+
+        Ok(tx_id)
+    }
+
+    #[cfg(feature = "sld2")]
+    pub fn set_approval_for_all(&mut self, args: SetApprovalForAllArgs) -> Result<Nat, SetApprovalForAllError> {
+        let caller = self.env.caller();
+        let tx_hash = self.check_tx(caller, "sld2:approve_for_all", &args, args.created_at_time)?;
+        let account = Account::new(caller, args.from_subaccount);
+        match args.approved {
+            true => {
+                let operators = self.operators.entry(account).or_default();
+                if operators.len() >= self.max_approvals && !operators.contains(&args.operator) {
+                    return Err(SetApprovalForAllError::MaxApprovals(Nat::from(self.max_approvals)));
+                }
+                operators.insert(args.operator);
+            }
+            false => {
+                if let Some(operators) = self.operators.get_mut(&account) {
+                    operators.remove(&args.operator);
+                    if operators.is_empty() {
+                        self.operators.remove(&account);
+                    }
+                }
+            }
+        }
+
+        self.write_tx(caller, TxKind::ApproveForAll {
+            operator: args.operator,
+            approved: args.approved,
+            subaccount: account.subaccount,
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+        });
+        let tx_id: Nat = self.tx_total.clone() - 1;
+        self.record_tx(tx_hash, &tx_id);
 
         Ok(tx_id)
     }
 
+    #[cfg(feature = "sld2")]
+    pub fn get_approved_for_all(&self, account: &Account) -> HashSet<&Principal> {
+        self.operators.get(account).map_or(HashSet::default(), |operators| operators.iter().collect())
+    }
+
+    /// Spender is approved for the token itself or is an operator of the token owner
+    #[cfg(feature = "sld2")]
+    fn is_approved(&self, token: &Token, spender: &Principal) -> bool {
+        token.approved.contains(spender) || self.operators
+            .get(&token.account)
+            .is_some_and(|operators| operators.contains(spender))
+    }
+
+    /// Approvals are only given through SLD-2, so without it nobody else than the owner is approved
+    #[cfg(not(feature = "sld2"))]
+    fn is_approved(&self, _token: &Token, _spender: &Principal) -> bool {
+        false
+    }
+
+    pub fn transfer(&mut self, args: TransferFromArgs) -> Result<Nat, TransferError> {
+        let caller = self.env.caller();
+        let tx_hash = self.check_tx(caller, "sld2:transfer_from", &args, args.created_at_time)?;
+        self.move_token(caller, tx_hash, args).map_err(|err| err.to_transfer_error())
+    }
+
     pub fn transfer_from(&mut self, args: TransferFromArgs) -> Result<Nat, TransferFromError> {
-        let minter_account = Account::minter();
-        let caller = caller();
+        let caller = self.env.caller();
+        let tx_hash = self.check_tx(caller, "sld2:transfer_from", &args, args.created_at_time)?;
+        self.move_token(caller, tx_hash, args)
+    }
+
+    fn move_token(&mut self, caller: Principal, tx_hash: Option<(u64, Hash)>, args: TransferFromArgs) -> Result<Nat, TransferFromError> {
+        let minter_account = self.minting_account();
         let caller_is_custodian = self.custodians.contains(&caller);
         let transfer_is_burn = args.to == minter_account;
-        let mut transfer_is_mint = false;
-        let mut token = self.tokens.get(&args.token_id).map_or_else(|| {
-            if !caller_is_custodian {
-                return Err(TransferFromError::NotFound);
-            }
-            transfer_is_mint = true;
-            Ok(Token {
-                account: minter_account,
-                tx_id: Offset::from(0),
-                approved: HashSet::default(),
-            })
-        }, |token| Ok(token.clone()))?;
+        let mut token = self.tokens.get(&args.token_id).cloned().ok_or(TransferFromError::NotFound)?;
         let caller_is_from = args.from.owner == caller;
-        let from_is_owner = token.account == args.from || (caller_is_custodian && token.account == minter_account);
-        let caller_is_approved = token.approved.contains(&caller);
+        let from_is_owner = token.account == args.from;
+        let caller_is_approved = self.is_approved(&token, &caller);
 
         if !from_is_owner {
             return Err(TransferFromError::NotOwner);
         }
 
+        if transfer_is_burn && !cfg!(feature = "sld5") {
+            return Err(TransferFromError::GenericError(GenericError {
+                error_code: Nat::from(400),
+                message: "Tokens can't be burned".into(),
+            }));
+        }
+
         if transfer_is_burn && !caller_is_custodian {
             return Err(TransferFromError::GenericError(GenericError {
                 error_code: Nat::from(403),
@@ -187,70 +824,144 @@ impl State {
         }
 
         if args.to == token.account {
-            return Err(TransferFromError::NotSelf);
-        }
-
-        token.account = args.to.clone();
-        let mut event = Event {
-            caller,
-            operation: (
-                if transfer_is_mint {
-                    "sld1:mint"
-                } else if transfer_is_burn {
-                    "sld1:burn"
-                } else if caller_is_from {
-                    "sld1:transfer"
-                } else {
-                    "sld2:transfer_from"
-                }
-            ).into(),
-            time: time(),
-            details: HashMap::from([
-                ("token_id".into(), Value::Nat(args.token_id.clone())),
-                ("time".into(), Value::Nat(Nat::from(time()))),
-                ("from_tx".into(), Value::Nat(token.tx_id.clone())),
-            ]),
-        };
-        if let Some(memo) = args.memo {
-            event.details.insert("memo".into(), Value::Blob(Vec::from(memo)));
-        }
-        if let Some(created_at_time) = args.created_at_time {
-            event.details.insert("time".into(), Value::Nat(Nat::from(created_at_time)));
+            return Err(TransferFromError::GenericError(GenericError {
+                error_code: Nat::from(400),
+                message: "Token is already owned by receiving account".into(),
+            }));
         }
-        self.write_tx(event);
+
+        let from_account = token.account;
+        token.account = args.to;
+        let token_id = args.token_id.clone();
+        let from_tx = token.tx_id.clone();
+        let memo = args.memo;
+        let created_at_time = args.created_at_time;
+        // Burns are rejected without SLD-5 and callers other than the owner without SLD-2
+        self.write_tx(caller, match (transfer_is_burn, caller_is_from) {
+            #[cfg(feature = "sld5")]
+            (true, _) => TxKind::Burn { token_id, from_tx, memo, created_at_time },
+            #[cfg(feature = "sld2")]
+            (false, false) => TxKind::TransferFrom { token_id, from_tx, memo, created_at_time },
+            _ => TxKind::Transfer { token_id, from_tx, memo, created_at_time },
+        });
         token.tx_id = self.tx_total.clone() - 1;
+        self.record_tx(tx_hash, &token.tx_id);
         token.approved = HashSet::default();
         self.tokens.insert(args.token_id.clone(), token);
+        self.index_token(&args.token_id, Some(from_account), args.to);
+        self.certify_token(&args.token_id);
+
+        Ok(self.tx_total.clone() - 1)
+    }
+
+    #[cfg(feature = "sld4")]
+    pub fn mint(&mut self, args: MintArgs) -> Result<Nat, MintError> {
+        let caller = self.env.caller();
+        if !self.custodians.contains(&caller) {
+            return Err(MintError::NotAllowed);
+        }
+        // Metadata is hashed on its own, the encoding of a map depends on the order of its entries
+        let metadata_hash = hash::hash_map(args.metadata.iter().map(|(key, value)| (key.as_str(), hash::hash_value(value))));
+        let tx = (&args.to, &args.token_id, metadata_hash, &args.memo);
+        let tx_hash = self.check_tx(caller, "sld4:mint", &tx, args.created_at_time)?;
+
+        // Burned tokens are kept with the minter account as owner, so
+        // token ids of burned tokens can never be minted a second time.
+        if self.tokens.contains_key(&args.token_id) {
+            return Err(MintError::GenericError(GenericError {
+                error_code: Nat::from(409),
+                message: "Token already exists".into(),
+            }));
+        }
+        if self.max_supply.is_some_and(|max_supply| self.tokens.len() >= max_supply) {
+            return Err(MintError::GenericError(GenericError {
+                error_code: Nat::from(400),
+                message: "Max supply has been reached".into(),
+            }));
+        }
+        if args.to == self.minting_account() {
+            return Err(MintError::GenericError(GenericError {
+                error_code: Nat::from(400),
+                message: "Token cannot be minted to minting account".into(),
+            }));
+        }
+
+        self.write_tx(caller, TxKind::Mint {
+            token_id: args.token_id.clone(),
+            to: args.to,
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+        });
+        let tx_id: Nat = self.tx_total.clone() - 1;
+        self.record_tx(tx_hash, &tx_id);
+        self.tokens.insert(args.token_id.clone(), Token {
+            account: args.to,
+            tx_id: tx_id.clone(),
+            approved: HashSet::default(),
+            metadata: args.metadata,
+            royalty: None,
+        });
+        self.index_token(&args.token_id, None, args.to);
+        self.certify_token(&args.token_id);
+        self.certify_token_metadata(&args.token_id);
+
+        Ok(tx_id)
+    }
+
+    #[cfg(feature = "sld5")]
+    pub fn burn(&mut self, args: BurnArgs) -> Result<Nat, BurnError> {
+        let minter_account = self.minting_account();
+        let caller = self.env.caller();
+        let tx_hash = self.check_tx(caller, "sld5:burn", &args, args.created_at_time)?;
+        let mut token = self.tokens
+            .get(&args.token_id)
+            .filter(|token| token.account != minter_account)
+            .cloned()
+            .ok_or_else(|| BurnError::GenericError(GenericError {
+                error_code: Nat::from(404),
+                message: "Token not found".into(),
+            }))?;
+
+        // Owner can burn tokens in any of its subaccounts, approved spenders
+        // and operators can burn tokens they're allowed to transfer.
+        let caller_is_owner = token.account.owner == caller;
+        let caller_is_approved = self.is_approved(&token, &caller);
+        if !caller_is_owner && !caller_is_approved {
+            return Err(BurnError::NotAllowed);
+        }
 
-        // Update http certified data
-        // self.hash_tree.insert(format!("/token/{}", token.id.to_string()), sha2::Sha256::digest(serde_json::to_vec(&token).unwrap().as_slice()).into());
-        // self.hash_tree.insert("/tokens".into(), sha2::Sha256::digest(serde_json::to_vec(&self.tokens).unwrap().as_slice()).into());
-        // self.hash_tree.insert("/total_supply".into(), sha2::Sha256::digest(self.total_supply().to_string().as_bytes()).into());
-        // let page = if self.history_total as usize % HISTORY_PAGE_SIZE == 0 {
-        //     (self.history_total as usize / HISTORY_PAGE_SIZE) - 1
-        // } else {
-        //     self.history_total as usize / HISTORY_PAGE_SIZE
-        // };
-        // self.hash_tree.insert(format!("/history/{}", page), sha2::Sha256::digest(serde_json::to_vec(&self.read_history(page)).unwrap().as_slice()).into());
-        // self.hash_tree.insert("/history_total".into(), sha2::Sha256::digest(self.history_total.to_string().as_bytes()).into());
-        // set_certified_data(&labeled_hash(b"http_assets", &self.hash_tree.root_hash()));
+        let from_account = token.account;
+        token.account = minter_account;
+        self.write_tx(caller, TxKind::Burn {
+            token_id: args.token_id.clone(),
+            from_tx: token.tx_id.clone(),
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+        });
+        token.tx_id = self.tx_total.clone() - 1;
+        self.record_tx(tx_hash, &token.tx_id);
+        token.approved = HashSet::default();
+        self.tokens.insert(args.token_id.clone(), token);
+        self.index_token(&args.token_id, Some(from_account), minter_account);
+        self.certify_token(&args.token_id);
 
         Ok(self.tx_total.clone() - 1)
     }
 
+    #[cfg(feature = "sld2")]
     pub fn get_approved(&self, token_id: &TokenId) -> HashSet<&Principal> {
         self.tokens.get(token_id).map_or(HashSet::default(), |token| token.approved.iter().collect())
     }
 
     pub fn set_custodian(&mut self, args: SetCustodianArgs) -> Result<Nat, SetCustodiansError> {
-        let caller = caller();
+        let caller = self.env.caller();
         if !self.custodians.contains(&caller) {
             return Err(SetCustodiansError::NotAllowed);
         }
         match args.approved {
             true => {
-                if self.custodians.len() == 256 {
-                    return Err(SetCustodiansError::MaxCustodians(Nat::from(256)));
+                if self.custodians.len() >= self.max_custodians && !self.custodians.contains(&args.custodian) {
+                    return Err(SetCustodiansError::MaxCustodians(Nat::from(self.max_custodians)));
                 }
                 self.custodians.insert(args.custodian);
             }
@@ -258,167 +969,1615 @@ impl State {
                 self.custodians.remove(&args.custodian);
             }
         }
+        self.write_custodian_tx(caller, args.custodian, args.approved);
 
-        let event = Event {
-            caller,
-            operation: "sld4:set_custodian".into(),
-            time: time(),
-            details: HashMap::from([
-                ("custodian".into(), Value::Text(args.custodian.to_string())),
-                ("approved".into(), Value::Nat(Nat::from(if args.approved { 1 } else { 0 }))),
-                ("from_tx".into(), Value::Nat(self.custodians_tx.clone())),
-            ]),
-        };
-        self.write_tx(event);
+        Ok(self.tx_total.clone() - 1)
+    }
+
+    fn write_custodian_tx(&mut self, caller: Principal, custodian: Principal, approved: bool) {
+        self.write_tx(caller, TxKind::SetCustodian {
+            custodian,
+            approved,
+            from_tx: self.custodians_tx.clone(),
+        });
 
         self.custodians_tx = self.tx_total.clone() - 1;
+    }
+
+    #[cfg(feature = "sld6")]
+    pub fn set_metadata(&mut self, args: SetMetadataArgs) -> Result<Nat, SetMetadataError> {
+        let caller = self.env.caller();
+        self.check_metadata(caller, &args.key)?;
+        validate_metadata(&args.key, &args.value)?;
+        match (args.key.as_str(), &args.value) {
+            ("sld1:name", Value::Text(name)) => {
+                self.name = name.clone();
+                self.certify("/".into());
+                self.certify("/name".into());
+            }
+            ("sld1:symbol", Value::Text(symbol)) => {
+                self.symbol = symbol.clone();
+                self.certify("/".into());
+                self.certify("/symbol".into());
+            }
+            _ => {}
+        }
+        self.metadata.insert(args.key.clone(), args.value.clone());
+        self.write_tx(caller, TxKind::SetMetadata {
+            key: args.key,
+            value: args.value,
+            from_tx: self.metadata_tx.clone(),
+        });
+        self.metadata_tx = self.tx_total.clone() - 1;
+
+        Ok(self.tx_total.clone() - 1)
+    }
+
+    #[cfg(feature = "sld6")]
+    pub fn remove_metadata(&mut self, key: String) -> Result<Nat, SetMetadataError> {
+        let caller = self.env.caller();
+        self.check_metadata(caller, &key)?;
+        if key == "sld1:name" || key == "sld1:symbol" {
+            return Err(SetMetadataError::GenericError(GenericError {
+                error_code: Nat::from(400),
+                message: format!("Metadata {} can't be removed", key),
+            }));
+        }
+        self.metadata.remove(&key);
+        self.write_tx(caller, TxKind::RemoveMetadata {
+            key,
+            from_tx: self.metadata_tx.clone(),
+        });
+        self.metadata_tx = self.tx_total.clone() - 1;
+
+        Ok(self.tx_total.clone() - 1)
+    }
+
+    /// Freeze metadata key so that it can't be set or removed anymore, freezing can't be undone
+    #[cfg(feature = "sld6")]
+    pub fn freeze_metadata(&mut self, key: String) -> Result<Nat, SetMetadataError> {
+        let caller = self.env.caller();
+        self.check_metadata(caller, &key)?;
+        self.frozen_metadata.insert(key.clone());
+        self.write_tx(caller, TxKind::FreezeMetadata {
+            key,
+            from_tx: self.metadata_tx.clone(),
+        });
+        self.metadata_tx = self.tx_total.clone() - 1;
+
+        Ok(self.tx_total.clone() - 1)
+    }
+
+    /// Only custodians can change metadata keys that have a valid format and are not frozen
+    #[cfg(feature = "sld6")]
+    fn check_metadata(&self, caller: Principal, key: &str) -> Result<(), SetMetadataError> {
+        if !self.custodians.contains(&caller) {
+            return Err(SetMetadataError::NotAllowed);
+        }
+        if !is_metadata_key(key) {
+            return Err(SetMetadataError::InvalidKey);
+        }
+        if self.frozen_metadata.contains(key) {
+            return Err(SetMetadataError::Frozen);
+        }
+        Ok(())
+    }
+
+    /// Royalty fee for the sale price of a token, falls back to the collection royalty when the
+    /// token has no royalty of its own. Without any royalty the fee is zero for the minter account.
+    #[cfg(feature = "sld7")]
+    pub fn royalty_fee(&self, token_id: Option<&TokenId>, price: &Nat) -> RoyaltyFee {
+        let royalty = token_id
+            .and_then(|token_id| self.tokens.get(token_id))
+            .and_then(|token| token.royalty.as_ref())
+            .or(self.royalty.as_ref());
+        match royalty {
+            Some(royalty) => RoyaltyFee {
+                account: royalty.account,
+                fee: price.clone() * royalty.fee / MAX_ROYALTY_FEE,
+            },
+            None => RoyaltyFee {
+                account: self.minting_account(),
+                fee: Nat::from(0),
+            }
+        }
+    }
+
+    #[cfg(feature = "sld8")]
+    pub fn set_royalty_fee(&mut self, args: SetRoyaltyFeeArgs) -> Result<Nat, SetRoyaltyFeeError> {
+        let caller = self.env.caller();
+        if !self.custodians.contains(&caller) {
+            return Err(SetRoyaltyFeeError::NotAllowed);
+        }
+        let royalty = Royalty {
+            account: args.account,
+            fee: args.fee.0
+                .to_u16()
+                .filter(|fee| *fee <= MAX_ROYALTY_FEE)
+                .ok_or_else(|| SetRoyaltyFeeError::GenericError(GenericError {
+                    error_code: Nat::from(400),
+                    message: format!("Royalty fee should be at most {} basis points", MAX_ROYALTY_FEE),
+                }))?,
+        };
+        match &args.token_id {
+            Some(token_id) => {
+                self.tokens.get_mut(token_id).ok_or_else(|| SetRoyaltyFeeError::GenericError(GenericError {
+                    error_code: Nat::from(404),
+                    message: "Token not found".into(),
+                }))?.royalty = Some(royalty);
+            }
+            None => {
+                self.royalty = Some(royalty);
+            }
+        }
+
+        self.write_tx(caller, TxKind::SetRoyaltyFee {
+            token_id: args.token_id,
+            account: args.account,
+            fee: args.fee,
+        });
 
         Ok(self.tx_total.clone() - 1)
     }
 
-    pub fn write_tx(&mut self, event: Event) {
+    /// Reject transactions with a created at time outside of the transaction window and
+    /// transactions that have already been submitted within the window as per ICRC-1.
+    ///
+    /// Returns the key that should be recorded once the transaction has been written,
+    /// transactions without a created at time are never deduplicated.
+    pub fn check_tx<T: CandidType>(&mut self, caller: Principal, operation: &str, args: &T, created_at_time: Option<u64>) -> Result<Option<(u64, Hash)>, TxError> {
+        let created_at_time = match created_at_time {
+            Some(created_at_time) => created_at_time,
+            None => return Ok(None)
+        };
+        let now = self.env.time();
+        if created_at_time.saturating_add(self.tx_window).saturating_add(self.permitted_drift) < now {
+            return Err(TxError::TooOld);
+        }
+        if created_at_time > now.saturating_add(self.permitted_drift) {
+            return Err(TxError::CreatedInFuture { ledger_time: now });
+        }
+
+        // Prune transactions that can no longer be submitted again
+        let oldest = now.saturating_sub(self.tx_window).saturating_sub(self.permitted_drift);
+        self.tx_hashes = self.tx_hashes.split_off(&(oldest, [0u8; 32]));
+
+        let bytes = candid::encode_args((caller, operation, args))
+            .unwrap_or_else(|err| trap(&format!("An error occurred when encoding transaction: {:?}", err)));
+        let key = (created_at_time, sha2::Sha256::digest(&bytes).into());
+        if let Some(duplicate_of) = self.tx_hashes.get(&key) {
+            return Err(TxError::Duplicate { duplicate_of: duplicate_of.clone() });
+        }
+
+        Ok(Some(key))
+    }
+
+    /// Record transaction so that it's deduplicated within the transaction window
+    pub fn record_tx(&mut self, key: Option<(u64, Hash)>, tx_id: &Nat) {
+        if let Some(key) = key {
+            self.tx_hashes.insert(key, tx_id.clone());
+        }
+    }
+
+    /// Write transaction to the log, it's chained to the previous transaction with its parent hash
+    pub fn write_tx(&mut self, caller: Principal, kind: TxKind) {
+        let block_id = self.block_indexes.len();
+        let mut event = kind.into_event(caller, self.env.time());
+        if let Some(tip_hash) = self.tip_hash {
+            event.details.insert(hash::PARENT_HASH_KEY.into(), Value::Blob(tip_hash.to_vec()));
+        }
+        self.tip_hash = Some(hash::hash_event(&event));
         self.current_block.push(event);
         self.tx_total += 1;
-        // TODO: Check if block size has been reached, write to stable memory when reached
-
-
-        // let entry = HistoryEntry {
-        //     token_id: token_id.clone(),
-        //     account: token.account,
-        //     time: Time::from(time()),
-        //     // Refer to self if offset is 0 (mint)
-        //     from_offset: if token.tx_id == 0 {
-        //         Offset::from(self.tx_total)
-        //     } else {
-        //         token.tx_id.clone()
-        //     },
-        // };
-        // self.history_entries.push(entry);
-        // self.tx_total += 1;
-        //
-        // if self.history_entries.len() == HISTORY_PAGE_SIZE {
-        //     // Create stable memory writer instance
-        //     let mut writer = StableWriter {
-        //         offset: 0,
-        //         capacity: stable_size(),
-        //     };
-        //
-        //     // Create bytes for all entries
-        //     let mut bytes = [0u8; HISTORY_ENTRY_BYTES * HISTORY_PAGE_SIZE];
-        //     for i in 0..HISTORY_PAGE_SIZE {
-        //         self.history_entries[i].to_stable_bytes(<&mut [u8; HISTORY_ENTRY_BYTES]>::try_from(&mut bytes[i * HISTORY_ENTRY_BYTES..(i + 1) * HISTORY_ENTRY_BYTES]).unwrap());
-        //     }
-        //
-        //     // Write to stable memory
-        //     writer.offset = (self.tx_total as usize - HISTORY_PAGE_SIZE) * HISTORY_ENTRY_BYTES;
-        //     writer.write(&bytes).map_err(|_| "An error occurred when writing to stable memory").unwrap();
-        //
-        //     // Clear current history
-        //     self.history_entries = vec![];
-        // }
+
+        // Seal current block and archive it to stable memory once block size has been reached
+        if self.current_block.len() == self.block_size {
+            let bytes = candid::encode_one(&self.current_block)
+                .unwrap_or_else(|err| trap(&format!("An error occurred when encoding block: {:?}", err)));
+            let mut writer = StableWriter::new(self.env.memory(), self.stable_offset());
+            writer.write(&bytes)
+                .unwrap_or_else(|err| trap(&format!("An error occurred when writing to stable memory: {:?}", err)));
+            self.block_indexes.push(writer.offset);
+            let hashes = http::body_hashes(&http::block(&self.current_block));
+            self.history_hashes.push(hashes.clone());
+            self.current_block = vec![];
+            self.certify_hashes(format!("/history/{}", block_id), Some(hashes));
+
+            // Next block is served while it's still empty, as it is after an upgrade
+            self.certify(format!("/history/{}", block_id + 1));
+        } else {
+            self.certify(format!("/history/{}", block_id));
+        }
     }
 
+    #[cfg(feature = "sld3")]
     pub fn read_tx(&self, tx_id: Nat) -> Option<EventOrBucket> {
-        tx_id.0
-            .to_usize()
-            .map(|index| self.current_block
-                .get(index)
-                .map(|event| EventOrBucket::Event(event.clone()))
-            )
-            .flatten()
-        // TODO: read from from stable memory if not in current block
+        let tx_id = tx_id.0.to_usize()?;
+        match self.read_block(Nat::from(tx_id / self.block_size))? {
+            BlockOrBucket::Block(block) => block
+                .get(tx_id % self.block_size)
+                .map(|event| EventOrBucket::Event(event.clone())),
+            BlockOrBucket::Bucket(bucket) => Some(EventOrBucket::Bucket(bucket))
+        }
     }
 
     pub fn read_block(&self, block_id: Nat) -> Option<BlockOrBucket> {
-        block_id.0
-            .to_usize()
-            .map(|_| BlockOrBucket::Block(self.current_block.clone()))
-        // TODO: read from from stable memory if not current block
-    }
-
-    // pub fn read_history(&self, page: usize) -> Vec<HistoryEntry> {
-    //     if (page + 1) * HISTORY_PAGE_SIZE > self.tx_total as usize {
-    //         // Return current history if page is not out of bounds else return empty history
-    //         if page * HISTORY_PAGE_SIZE < self.tx_total as usize {
-    //             self.history_entries.clone()
-    //         } else {
-    //             vec![]
-    //         }
-    //     } else {
-    //         // Create stable memory reader instance
-    //         let mut reader = StableReader {
-    //             offset: page as usize * HISTORY_PAGE_SIZE * HISTORY_ENTRY_BYTES,
-    //             capacity: stable_size(),
-    //         };
-    //
-    //         // Read all bytes of entries range, create entries from bytes and return them with total count
-    //         let mut buf = vec![0u8; HISTORY_PAGE_SIZE * HISTORY_ENTRY_BYTES];
-    //         reader.read(buf.as_mut_slice()).map_err(|_| "An error occurred when reading from stable memory").unwrap();
-    //         let mut entries: Vec<HistoryEntry> = Vec::with_capacity(HISTORY_PAGE_SIZE);
-    //         for i in 0..HISTORY_PAGE_SIZE {
-    //             let entry_offset = i * HISTORY_ENTRY_BYTES;
-    //             let bytes = &buf[entry_offset..entry_offset + HISTORY_ENTRY_BYTES];
-    //             entries.push(HistoryEntry::from_stable_bytes(<&[u8; HISTORY_ENTRY_BYTES]>::try_from(bytes).unwrap()));
-    //         }
-    //         entries
-    //     }
-    // }
-
-    // pub fn http_request(&self, req: HttpRequest) -> HttpResponse {
-    //     // Create certification header, should always be returned for every request including
-    //     // requests that return 404 not found. Else the receiving client does not know if the 404
-    //     // not found is sent by the canister or a malicious middle man.
-    //     let certificate = data_certificate().unwrap_or_else(|| trap("No data certificate available"));
-    //     let hash_tree = labeled(b"http_assets", self.hash_tree.witness(req.url.as_bytes()));
-    //     let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
-    //     serializer.self_describe().unwrap();
-    //     hash_tree.serialize(&mut serializer).unwrap();
-    //     let headers = vec![
-    //         ("Content-Type".to_string(), "application/json".to_string()),
-    //         (
-    //             "IC-Certificate".to_string(),
-    //             String::from("certificate=:")
-    //                 + &base64::encode(certificate)
-    //                 + ":, tree=:"
-    //                 + &base64::encode(&serializer.into_inner())
-    //                 + ":",
-    //         ),
-    //     ];
-    //
-    //     // No certified hash found for request url and method, return 404 response with absence proof
-    //     if self.hash_tree.get(req.url.as_bytes()).is_none() || req.method != "GET" {
-    //         return HttpResponse {
-    //             status_code: 404,
-    //             headers,
-    //             body: RcBytes::default(),
-    //             streaming_strategy: None,
-    //         };
-    //     }
-    //
-    //     // Certified hash was found for request, from here on we can assume
-    //     // the url is valid and simply unwrap everything without checks.
-    //     let mut parts = req.url.split("/").skip(1).fuse();
-    //     let body = RcBytes::from(ByteBuf::from(match parts.next() {
-    //         Some("name") => format!("\"{}\"", self.name),
-    //         Some("symbol") => format!("\"{}\"", self.symbol),
-    //         Some("total_supply") => self.total_supply().to_string(),
-    //         Some("token") => {
-    //             "hmm".to_string()
-    //             // let token_id = Principal::from_text(parts.next().unwrap()).unwrap();
-    //             // let token = self.token(token_id).unwrap();
-    //             // serde_json::to_string(token).unwrap()
-    //         }
-    //         Some("tokens") => serde_json::to_string(&self.tokens).unwrap(),
-    //         Some("history") => serde_json::to_string(&self.read_history(parts.next().unwrap().parse::<usize>().unwrap())).unwrap(),
-    //         Some("history_total") => self.tx_total.to_string(),
-    //         _ => unreachable!()
-    //     }));
-    //
-    //     HttpResponse {
-    //         status_code: 200,
-    //         headers,
-    //         body,
-    //         streaming_strategy: None,
-    //     }
-    // }
-}
\ No newline at end of file
+        let block_id = block_id.0.to_usize()?;
+
+        // Blocks that have been offloaded are answered with the bucket that holds them
+        if let Some((bucket, _)) = self.buckets.iter().find(|(_, end)| block_id < *end) {
+            return Some(BlockOrBucket::Bucket(*bucket));
+        }
+
+        match block_id.cmp(&self.block_indexes.len()) {
+            // Sealed blocks are read from stable memory, each block
+            // starts where the previous sealed block has ended.
+            Ordering::Less => {
+                let start = if block_id == 0 { HEADER_BYTES } else { self.block_indexes[block_id - 1] };
+                let mut reader = StableReader::new(self.env.memory(), start);
+                let mut bytes = vec![0u8; self.block_indexes[block_id] - start];
+                reader.read(&mut bytes)
+                    .unwrap_or_else(|err| trap(&format!("An error occurred when reading from stable memory: {:?}", err)));
+                let block = candid::decode_one::<Vec<Event>>(&bytes)
+                    .unwrap_or_else(|err| trap(&format!("An error occurred when decoding block: {:?}", err)));
+                Some(BlockOrBucket::Block(block))
+            }
+            Ordering::Equal => Some(BlockOrBucket::Block(self.current_block.clone())),
+            Ordering::Greater => None
+        }
+    }
+
+    /// Number of sealed blocks that have been offloaded to buckets
+    pub fn offloaded_blocks(&self) -> usize {
+        self.buckets.last().map_or(0, |(_, end)| *end)
+    }
+
+    /// Lock the transaction log for offloading, only a single offload can run at the same time
+    #[cfg(feature = "sld3")]
+    pub fn start_offload(&mut self, caller: Principal) -> Result<(), OffloadBlocksError> {
+        if !self.custodians.contains(&caller) {
+            return Err(OffloadBlocksError::NotAllowed);
+        }
+        if self.offloading {
+            return Err(OffloadBlocksError::TemporarilyUnavailable);
+        }
+        self.offloading = true;
+        Ok(())
+    }
+
+    #[cfg(feature = "sld3")]
+    pub fn end_offload(&mut self) {
+        self.offloading = false;
+    }
+
+    /// Next sealed block that should be offloaded, if any
+    #[cfg(feature = "sld3")]
+    pub fn next_offload_block(&self) -> Option<(usize, Vec<Event>)> {
+        let block_id = self.offloaded_blocks();
+        if block_id >= self.block_indexes.len() {
+            return None;
+        }
+        match self.read_block(Nat::from(block_id))? {
+            BlockOrBucket::Block(block) => Some((block_id, block)),
+            BlockOrBucket::Bucket(_) => None
+        }
+    }
+
+    /// Mark block as offloaded to bucket, consecutive blocks in the same bucket share a single entry
+    #[cfg(feature = "sld3")]
+    pub fn offload_block(&mut self, bucket: Principal, block_id: usize) {
+        match self.buckets.last_mut() {
+            Some((last_bucket, end)) if *last_bucket == bucket => *end = block_id + 1,
+            _ => self.buckets.push((bucket, block_id + 1))
+        }
+
+        // Offloaded blocks are no longer served by the http interface
+        self.certify(format!("/history/{}", block_id));
+    }
+
+    /// Transactions within the given ranges as ICRC-3 blocks, offloaded transactions are returned as
+    /// ranges that should be fetched from their bucket. Ranges are cut off at the end of the log.
+    #[cfg(feature = "sld3")]
+    pub fn get_blocks(&self, args: Vec<BlockRange>) -> GetBlocksResult {
+        let log_length = self.tx_total.0.to_usize().unwrap_or(usize::MAX);
+        let mut blocks = vec![];
+        let mut archived_blocks: Vec<ArchivedBlocks> = vec![];
+        let mut bytes = 0;
+        // Stop at the first transaction that exceeds the limits, ranges after it are left
+        // out entirely so that a client can continue from the last block it received.
+        'ranges: for range in args {
+            let start = range.start.0.to_usize().unwrap_or(usize::MAX).min(log_length);
+            let end = start.saturating_add(range.length.0.to_usize().unwrap_or(usize::MAX)).min(log_length);
+            let mut tx_id = start;
+            while tx_id < end {
+                let block_id = tx_id / self.block_size;
+                let block_start = block_id * self.block_size;
+                match self.read_block(Nat::from(block_id)) {
+                    Some(BlockOrBucket::Block(block)) => {
+                        let block_end = (block_start + block.len()).min(end);
+                        for event in &block[tx_id - block_start..block_end - block_start] {
+                            let block = icrc3::block(event);
+                            bytes += candid::encode_one(&block).map_or(0, |block| block.len());
+                            if blocks.len() == MAX_BLOCKS_PER_REQUEST || bytes > MAX_PAGE_BYTES {
+                                break 'ranges;
+                            }
+                            blocks.push(BlockWithId { id: Nat::from(tx_id), block });
+                            tx_id += 1;
+                        }
+                    }
+                    Some(BlockOrBucket::Bucket(bucket)) => {
+                        // Bucket holds all blocks up to its end, so the range can be
+                        // passed on as a whole up to where the bucket ends.
+                        let bucket_end = self.buckets
+                            .iter()
+                            .find(|(_, end)| block_id < *end)
+                            .map_or(end, |(_, end)| (end * self.block_size).min(log_length));
+                        let range = BlockRange {
+                            start: Nat::from(tx_id),
+                            length: Nat::from(bucket_end.min(end) - tx_id),
+                        };
+                        match archived_blocks.iter_mut().find(|archived| archived.callback.0.principal == bucket) {
+                            Some(archived) => archived.args.push(range),
+                            None => archived_blocks.push(ArchivedBlocks {
+                                args: vec![range],
+                                callback: GetBlocksCallback(Func {
+                                    principal: bucket,
+                                    method: "icrc3_get_blocks".into(),
+                                }),
+                            }),
+                        }
+                        tx_id = bucket_end.min(end);
+                    }
+                    None => break
+                }
+            }
+        }
+        GetBlocksResult {
+            log_length: self.tx_total.clone(),
+            blocks,
+            archived_blocks,
+        }
+    }
+
+    /// Buckets with the range of transactions that they hold, starting after the given bucket
+    #[cfg(feature = "sld3")]
+    pub fn get_archives(&self, from: Option<Principal>) -> Vec<ArchiveInfo> {
+        let mut start = 0;
+        let archives = self.buckets.iter().map(|(bucket, end)| {
+            let archive = ArchiveInfo {
+                canister_id: *bucket,
+                start: Nat::from(start * self.block_size),
+                end: Nat::from(end * self.block_size - 1),
+            };
+            start = *end;
+            archive
+        });
+        match from {
+            Some(from) => archives.skip_while(|archive| archive.canister_id != from).skip(1).collect(),
+            None => archives.collect()
+        }
+    }
+
+    /// Certificate of the index and hash of the last transaction, so that the whole
+    /// log can be verified by following the parent hashes from the tip.
+    #[cfg(feature = "sld3")]
+    pub fn tip_certificate(&self) -> Option<DataCertificate> {
+        let certificate = self.env.data_certificate()?;
+        self.tip_hash?;
+        let http_assets = HashTree::Pruned(labeled_hash(b"http_assets", &self.hash_tree.root_hash()));
+        Some(DataCertificate {
+            certificate: ByteBuf::from(certificate),
+            hash_tree: ByteBuf::from(hash::encode_tree(&self.certified_tree(http_assets, true))),
+        })
+    }
+
+    pub fn http_request(&self, req: HttpRequest) -> HttpResponse {
+        let path = req.url.split('?').next().unwrap_or_default();
+
+        // Only bodies with a certified hash are served, else return 404 response with absence proof
+        let body = match req.method.as_str() {
+            "GET" if self.hash_tree.get(path.as_bytes()).is_some() => self.http_body(path),
+            _ => None
+        };
+
+        // Create certification header, should always be returned for every request including
+        // requests that return 404 not found. Else the receiving client does not know if the 404
+        // not found is sent by the canister or a malicious middle man. Streamed responses
+        // also reveal the chunk hashes that are returned in the streaming callback tokens.
+        let certificate = self.env.data_certificate().unwrap_or_else(|| trap("No data certificate available"));
+        let streamed = body.as_ref().is_some_and(|(_, body)| body.len() > http::MAX_CHUNK_BYTES);
+        let witness = if streamed {
+            self.hash_tree.value_range(path.as_bytes(), http::chunk_keys_end(path).as_bytes())
+        } else {
+            self.hash_tree.witness(path.as_bytes())
+        };
+        let certificate_header = http::certificate_header(&certificate, self.certified_tree(labeled(b"http_assets", witness), false));
+
+        match body {
+            Some((content_type, body)) => HttpResponse {
+                status_code: 200,
+                headers: vec![("Content-Type".into(), content_type.into()), certificate_header],
+                body: RcBytes::from(ByteBuf::from(body.chunks(http::MAX_CHUNK_BYTES).next().unwrap_or_default())),
+                streaming_strategy: self.streaming_token(path, 1).map(|token| StreamingStrategy::Callback {
+                    callback: StreamingCallback(Func {
+                        principal: self.env.id(),
+                        method: "http_request_streaming_callback".into(),
+                    }),
+                    token,
+                }),
+            },
+            None => HttpResponse {
+                status_code: 404,
+                headers: vec![("Content-Type".into(), "application/json".into()), certificate_header],
+                body: RcBytes::default(),
+                streaming_strategy: None,
+            }
+        }
+    }
+
+    /// Next chunk of a streamed body, tokens of chunks that aren't certified are rejected
+    /// so that an invalid token is never mistaken for the end of the body.
+    pub fn http_request_streaming_callback(&self, token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+        let chunk = token.index.0.to_usize()
+            .filter(|index| self.hash_tree.get(http::chunk_key(&token.key, *index).as_bytes()).is_some())
+            .and_then(|index| Some((index, self.http_body(&token.key)?.1
+                .chunks(http::MAX_CHUNK_BYTES)
+                .nth(index)
+                .map(Vec::from)?)));
+        match chunk {
+            Some((index, chunk)) => StreamingCallbackHttpResponse {
+                body: RcBytes::from(ByteBuf::from(chunk)),
+                token: self.streaming_token(&token.key, index + 1),
+            },
+            None => trap("Invalid streaming callback token")
+        }
+    }
+
+    /// Token of the chunk at the given index with its certified hash, none if there's no such chunk
+    fn streaming_token(&self, path: &str, index: usize) -> Option<StreamingCallbackToken> {
+        self.hash_tree.get(http::chunk_key(path, index).as_bytes()).map(|hash| StreamingCallbackToken {
+            key: path.into(),
+            content_encoding: "identity".into(),
+            index: Nat::from(index),
+            sha256: Some(ByteBuf::from(hash.to_vec())),
+        })
+    }
+}
+
+/// Take token ids up to the limit (capped at page size) without exceeding the max page
+/// bytes, the Candid encoded size of a nat is the length of its LEB128 encoding. The token
+/// id that doesn't fit anymore is left in the iterator for the next page.
+fn take_page<'a, I: Iterator<Item=&'a TokenId>>(token_ids: &mut Peekable<I>, limit: Option<&Nat>, page_size: usize) -> Vec<&'a TokenId> {
+    let limit = limit.and_then(|limit| limit.0.to_usize()).map_or(page_size, |limit| limit.min(page_size));
+    let mut bytes = 0;
+    let mut page = vec![];
+    while page.len() < limit {
+        match token_ids.next_if(|token_id| bytes + encoded_len(token_id) <= MAX_PAGE_BYTES) {
+            Some(token_id) => {
+                bytes += encoded_len(token_id);
+                page.push(token_id);
+            }
+            None => break,
+        }
+    }
+    page
+}
+
+/// Page of token ids, walks the pages before it since their sizes depend on the token ids
+fn nth_page<'a>(token_ids: impl Iterator<Item=&'a TokenId>, page: &Nat, page_size: usize) -> Vec<&'a TokenId> {
+    let mut token_ids = token_ids.peekable();
+    let page = match page.0.to_usize() {
+        Some(page) => page,
+        None => return vec![],
+    };
+    for _ in 0..page {
+        if take_page(&mut token_ids, None, page_size).is_empty() {
+            return vec![];
+        }
+    }
+    take_page(&mut token_ids, None, page_size)
+}
+
+fn encoded_len(token_id: &TokenId) -> usize {
+    (token_id.0.bits() as usize).div_ceil(7).max(1)
+}
+
+/// Positive number that fits in a usize, used to validate init and upgrade arguments
+fn positive(value: &Nat, name: &str) -> Result<usize, String> {
+    value.0.to_usize().filter(|value| *value > 0).ok_or_else(|| format!("{} should be a positive number", name))
+}
+
+/// Metadata keys follow the `<namespace>:<key>` format, where the namespace doesn't contain colons
+fn is_metadata_key(key: &str) -> bool {
+    matches!(key.split_once(':'), Some((namespace, key)) if !namespace.is_empty() && !key.is_empty())
+}
+
+/// Namespace `sld1` is reserved for the standard metadata entries, which all have a text value
+fn validate_metadata(key: &str, value: &Value) -> Result<(), SetMetadataError> {
+    match (key, value) {
+        ("sld1:name" | "sld1:symbol" | "sld1:logo", Value::Text(_)) => Ok(()),
+        ("sld1:name" | "sld1:symbol" | "sld1:logo", _) => Err(SetMetadataError::GenericError(GenericError {
+            error_code: Nat::from(400),
+            message: format!("Metadata {} should be text", key),
+        })),
+        _ if key.starts_with("sld1:") || !is_metadata_key(key) => Err(SetMetadataError::InvalidKey),
+        _ => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use crate::env::MockEnvironment;
+
+    use super::*;
+
+    /// Time of the mock environment, transactions are created at or around this time
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    #[cfg(feature = "sld4")]
+    fn account(id: u8) -> Account {
+        Account::new(principal(id), None)
+    }
+
+    fn custodian() -> Principal {
+        principal(1)
+    }
+
+    fn init_args(block_size: usize) -> InitArgs {
+        InitArgs {
+            name: "Domains".into(),
+            symbol: "DOM".into(),
+            logo: None,
+            metadata: HashMap::default(),
+            custodians: vec![custodian()],
+            max_supply: None,
+            max_approvals: None,
+            max_custodians: None,
+            page_size: None,
+            block_size: Some(Nat::from(block_size)),
+            tx_window: None,
+            permitted_drift: None,
+        }
+    }
+
+    /// State that is initialized by an installer that isn't a custodian itself,
+    /// the mock environment is shared so that the caller and time can be changed.
+    fn init_state(block_size: usize) -> (State, Rc<MockEnvironment>) {
+        let env = Rc::new(MockEnvironment {
+            caller: Cell::new(principal(0)),
+            time: Cell::new(NOW),
+            id: principal(100),
+            ..MockEnvironment::default()
+        });
+        let mut state = State::with_env(Box::new(env.clone()));
+        state.init(init_args(block_size)).unwrap();
+        (state, env)
+    }
+
+    /// Block as read back from the log, either the current block or a sealed block from memory
+    fn block(state: &State, block_id: usize) -> Vec<Event> {
+        match state.read_block(Nat::from(block_id)) {
+            Some(BlockOrBucket::Block(block)) => block,
+            _ => panic!("Block {} is not in the log", block_id)
+        }
+    }
+
+    fn tx(state: &State, tx_id: usize) -> TxKind {
+        TxKind::try_from_event(&block(state, tx_id / state.block_size)[tx_id % state.block_size]).unwrap()
+    }
+
+    fn http_request(method: &str, url: &str) -> HttpRequest {
+        HttpRequest {
+            method: method.into(),
+            url: url.into(),
+            headers: vec![],
+            body: ByteBuf::default(),
+        }
+    }
+
+    /// Certificate and CBOR encoded hash tree in the certificate header of a response
+    fn certificate_header(response: &HttpResponse) -> (Vec<u8>, serde_cbor::Value) {
+        let (_, value) = response.headers.iter().find(|(name, _)| name == "IC-Certificate").unwrap();
+        let (certificate, tree) = value.split_once(", ").unwrap();
+        let decode = |field: &str, prefix: &str| base64::decode(field.strip_prefix(prefix).unwrap().trim_end_matches(':')).unwrap();
+        (decode(certificate, "certificate=:"), serde_cbor::from_slice(&decode(tree, "tree=:")).unwrap())
+    }
+
+    fn decode_tree(value: &serde_cbor::Value) -> HashTree<'_> {
+        use std::convert::TryInto;
+
+        use serde_cbor::Value::{Array, Bytes, Integer, Tag};
+        match value {
+            Tag(_, value) => decode_tree(value),
+            Array(items) => match items.as_slice() {
+                [Integer(0)] => HashTree::Empty,
+                [Integer(1), left, right] => fork(decode_tree(left), decode_tree(right)),
+                [Integer(2), Bytes(label), tree] => labeled(label, decode_tree(tree)),
+                [Integer(3), Bytes(leaf)] => HashTree::Leaf(Cow::Borrowed(leaf)),
+                [Integer(4), Bytes(hash)] => HashTree::Pruned(hash.as_slice().try_into().unwrap()),
+                _ => panic!("Invalid hash tree node {:?}", items)
+            },
+            _ => panic!("Invalid hash tree node {:?}", value)
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Lookup<'a> {
+        Found(&'a [u8]),
+        Absent,
+        Unknown,
+    }
+
+    /// Look up a path in a hash tree as defined in the IC interface specification
+    fn lookup<'a>(tree: &'a HashTree<'a>, path: &[&[u8]]) -> Lookup<'a> {
+        fn flatten<'a>(tree: &'a HashTree<'a>) -> Vec<&'a HashTree<'a>> {
+            match tree {
+                HashTree::Empty => vec![],
+                HashTree::Fork(forks) => [flatten(&forks.0), flatten(&forks.1)].concat(),
+                _ => vec![tree],
+            }
+        }
+        fn find_label<'a>(label: &[u8], trees: &[&'a HashTree<'a>]) -> Result<&'a HashTree<'a>, Lookup<'a>> {
+            let label_of = |tree: &&'a HashTree<'a>| match tree {
+                HashTree::Labeled(label, _) => Some(*label),
+                _ => None,
+            };
+            if let Some(HashTree::Labeled(_, tree)) = trees.iter().find(|tree| label_of(tree) == Some(label)) {
+                return Ok(tree);
+            }
+            // Absence is only proven by labels next to each other without any pruned subtree in between
+            let absent = match (trees.first().and_then(label_of), trees.last().and_then(label_of)) {
+                _ if trees.is_empty() || matches!(trees, [HashTree::Leaf(_)]) => true,
+                (Some(first), _) if label < first => true,
+                (_, Some(last)) if last < label => true,
+                _ => trees.windows(2).any(|pair| matches!(
+                    (label_of(&pair[0]), label_of(&pair[1])),
+                    (Some(left), Some(right)) if left < label && label < right
+                )),
+            };
+            Err(if absent { Lookup::Absent } else { Lookup::Unknown })
+        }
+        match path.split_first() {
+            None => match tree {
+                HashTree::Leaf(leaf) => Lookup::Found(leaf),
+                HashTree::Empty => Lookup::Absent,
+                _ => Lookup::Unknown,
+            },
+            Some((label, rest)) => match find_label(label, &flatten(tree)) {
+                Ok(tree) => lookup(tree, rest),
+                Err(result) => result,
+            }
+        }
+    }
+
+    #[cfg(feature = "sld4")]
+    fn mint_token(state: &mut State, env: &MockEnvironment, token_id: u64, to: Account) -> Nat {
+        env.caller.set(custodian());
+        state.mint(MintArgs {
+            to,
+            token_id: Nat::from(token_id),
+            metadata: HashMap::default(),
+            memo: None,
+            created_at_time: None,
+        }).unwrap()
+    }
+
+    /// State with sealed blocks and a current block, with tokens, approvals and
+    /// royalties for each of the standards that are enabled.
+    fn populated_state() -> (State, Rc<MockEnvironment>) {
+        let (mut state, env) = init_state(3);
+        env.caller.set(custodian());
+        #[cfg(feature = "sld6")]
+        state.set_metadata(SetMetadataArgs { key: "app:website".into(), value: Value::Text("https://example.com".into()) }).unwrap();
+        // Without metadata the block is sealed by custodian changes alone
+        #[cfg(not(feature = "sld6"))]
+        state.set_custodian(SetCustodianArgs { custodian: principal(10), approved: true }).unwrap();
+        state.set_custodian(SetCustodianArgs { custodian: principal(9), approved: true }).unwrap();
+        #[cfg(feature = "sld4")]
+        {
+            for token_id in 1..=5 {
+                mint_token(&mut state, &env, token_id, account(token_id as u8 % 2 + 2));
+            }
+            env.caller.set(principal(2));
+            state.transfer_from(transfer_args(account(2), account(4), 2)).unwrap();
+        }
+        #[cfg(all(feature = "sld4", feature = "sld5"))]
+        state.burn(BurnArgs { token_id: Nat::from(4), memo: None, created_at_time: Some(NOW) }).unwrap();
+        #[cfg(all(feature = "sld2", feature = "sld4"))]
+        {
+            env.caller.set(principal(3));
+            state.set_approval(SetApprovalArgs { from_subaccount: None, spender: principal(5), token_id: Nat::from(1), approved: true, memo: None, created_at_time: None }).unwrap();
+            state.set_approval_for_all(SetApprovalForAllArgs { from_subaccount: None, operator: principal(6), approved: true, memo: None, created_at_time: None }).unwrap();
+        }
+        #[cfg(all(feature = "sld4", feature = "sld8"))]
+        {
+            env.caller.set(custodian());
+            state.set_royalty_fee(SetRoyaltyFeeArgs { token_id: Some(Nat::from(3)), account: account(7), fee: Nat::from(500) }).unwrap();
+        }
+        assert!(!state.block_indexes.is_empty());
+        (state, env)
+    }
+
+    /// Restored state should be equal to the given state, including everything that is derived
+    fn assert_restored(state: &State, restored: &State) {
+        assert_eq!(restored.tokens, state.tokens);
+        assert_eq!(restored.owners, state.owners);
+        assert_eq!(restored.supply, state.supply);
+        assert_eq!(restored.metadata, state.metadata);
+        assert_eq!(restored.custodians, state.custodians);
+        #[cfg(feature = "sld2")]
+        assert_eq!(restored.operators, state.operators);
+        assert_eq!(restored.tx_total, state.tx_total);
+        assert_eq!(restored.tx_hashes, state.tx_hashes);
+        assert_eq!(restored.block_indexes, state.block_indexes);
+        assert_eq!(restored.current_block.len(), state.current_block.len());
+        assert_eq!(restored.tip_hash, state.tip_hash);
+
+        // Shape of the rebuilt tree depends on the insertion order, so its root hash can differ
+        // from the root hash of the original tree while both certify the very same hashes.
+        let hashes = |state: &State| state.hash_tree.iter().map(|(path, hash)| (path.clone(), *hash)).collect::<Vec<_>>();
+        assert_eq!(hashes(restored), hashes(state));
+    }
+
+    #[cfg(feature = "sld4")]
+    fn transfer_args(from: Account, to: Account, token_id: u64) -> TransferFromArgs {
+        TransferFromArgs {
+            from,
+            to,
+            token_id: Nat::from(token_id),
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    #[test]
+    fn init() {
+        let (state, env) = init_state(DEFAULT_BLOCK_SIZE);
+        assert_eq!(state.name, "Domains");
+        assert_eq!(state.metadata.get("sld1:symbol"), Some(&Value::Text("DOM".into())));
+        assert_eq!(state.custodians, HashSet::from([custodian()]));
+        assert_eq!(state.tx_total, Nat::from(1));
+        assert_eq!(tx(&state, 0), TxKind::SetCustodian { custodian: custodian(), approved: true, from_tx: Nat::from(0) });
+        assert_eq!(state.current_block[0].caller, principal(0));
+        assert_eq!(state.total_supply(), Nat::from(0));
+        assert!(state.hash_tree.get(b"/name").is_some());
+        assert!(!env.certified_data.borrow().is_empty());
+    }
+
+    #[test]
+    fn init_validates_args() {
+        let mut state = State::with_env(Box::new(MockEnvironment::default()));
+        assert!(state.init(InitArgs { custodians: vec![], ..init_args(1) }).is_err());
+        assert!(state.init(InitArgs { block_size: Some(Nat::from(0)), ..init_args(1) }).is_err());
+        assert!(state.init(InitArgs { custodians: vec![principal(1), principal(2)], max_custodians: Some(Nat::from(1)), ..init_args(1) }).is_err());
+        assert!(state.init(InitArgs { metadata: HashMap::from([("sld1:name".into(), Value::Nat(Nat::from(1)))]), ..init_args(1) }).is_err());
+    }
+
+    #[cfg(feature = "sld4")]
+    #[test]
+    fn mint() {
+        let (mut state, env) = init_state(DEFAULT_BLOCK_SIZE);
+        let tx_id = mint_token(&mut state, &env, 1, account(2));
+        assert_eq!(tx_id, Nat::from(1));
+        assert_eq!(tx(&state, 1), TxKind::Mint { token_id: Nat::from(1), to: account(2), memo: None, created_at_time: None });
+        assert_eq!(state.owner_of(&Nat::from(1)), Some(account(2)));
+        assert_eq!(state.balance_of(&account(2)), Nat::from(1));
+        assert_eq!(state.total_supply(), Nat::from(1));
+        assert_eq!(state.tokens(&Nat::from(0)), vec![&Nat::from(1)]);
+        assert_eq!(state.tokens[&Nat::from(1)].tx_id, tx_id);
+
+        let args = MintArgs { to: account(2), token_id: Nat::from(2), metadata: HashMap::default(), memo: None, created_at_time: None };
+        env.caller.set(principal(2));
+        assert!(matches!(state.mint(args.clone()), Err(MintError::NotAllowed)));
+        env.caller.set(custodian());
+        assert!(matches!(state.mint(MintArgs { token_id: Nat::from(1), ..args.clone() }), Err(MintError::GenericError(err)) if err.error_code == 409));
+        assert!(matches!(state.mint(MintArgs { to: state.minting_account(), ..args.clone() }), Err(MintError::GenericError(_))));
+        state.max_supply = Some(1);
+        assert!(matches!(state.mint(args), Err(MintError::GenericError(_))));
+        assert_eq!(state.tx_total, Nat::from(2));
+    }
+
+    #[test]
+    fn token_pages() {
+        let (mut state, _) = init_state(DEFAULT_BLOCK_SIZE);
+        let owner = Account::new(principal(2), None);
+        // Token ids of 37 bytes each, so that a page of the default page size doesn't fit in a message
+        let base = Nat::from(u128::MAX) * Nat::from(u128::MAX);
+        for token_id in 0..60_000u64 {
+            state.tokens.insert(base.clone() + Nat::from(token_id), Token {
+                account: owner,
+                tx_id: Nat::from(0),
+                approved: HashSet::default(),
+                metadata: HashMap::default(),
+                royalty: None,
+            });
+        }
+        state.rebuild_owners();
+
+        let page = state.tokens(&Nat::from(0));
+        assert_eq!(page.len(), MAX_PAGE_BYTES / 37);
+        assert_eq!(page, state.tokens_after(None, None));
+        assert_eq!(state.tokens_of(&owner, &Nat::from(0)), page);
+        assert_eq!(state.tokens_of_after(&owner, None, Some(&Nat::from(10))), page[..10].to_vec());
+        assert_eq!(state.tokens_after(page.last().copied(), None).len(), 60_000 - page.len());
+
+        // Walking every page returns the whole collection in order
+        let all_tokens: Vec<_> = state.tokens.keys().collect();
+        let pages: Vec<_> = (0..).map(|page| state.tokens(&Nat::from(page as u64))).take_while(|page| !page.is_empty()).collect();
+        assert_eq!(pages.iter().map(Vec::len).collect::<Vec<_>>(), vec![MAX_PAGE_BYTES / 37, 60_000 - MAX_PAGE_BYTES / 37]);
+        assert_eq!(pages.concat(), all_tokens);
+        let pages: Vec<_> = (0..).map(|page| state.tokens_of(&owner, &Nat::from(page as u64))).take_while(|page| !page.is_empty()).collect();
+        assert_eq!(pages.concat(), all_tokens);
+        assert!(state.tokens(&Nat::from(u128::MAX)).is_empty());
+
+        // Pages of a smaller page size also cover the whole collection
+        state.page_size = 7_000;
+        let pages: Vec<_> = (0..).map(|page| state.tokens(&Nat::from(page as u64))).take_while(|page| !page.is_empty()).collect();
+        assert_eq!(pages.len(), 9);
+        assert_eq!(pages.concat(), all_tokens);
+    }
+
+    #[test]
+    fn http_certificate() {
+        let (state, env) = populated_state();
+        let last_block = state.block_indexes.len();
+        let mut paths = vec!["/".to_string(), "/name".into(), "/symbol".into(), "/total_supply".into(), "/tokens".into()];
+        paths.extend((0..=last_block).map(|block_id| format!("/history/{}", block_id)));
+        #[cfg(feature = "sld4")]
+        paths.extend(["/token/1".to_string(), "/token/2".into(), "/tokens/0".into()]);
+
+        // Each body is proven by the tree in its certificate header, query strings are ignored
+        for path in &paths {
+            for url in [path.clone(), format!("{}?page=1", path)] {
+                let response = state.http_request(http_request("GET", &url));
+                assert_eq!(response.status_code, 200, "{}", url);
+                assert!(response.streaming_strategy.is_none());
+                assert_eq!(Some(response.body.to_vec()), state.http_body(path).map(|(_, body)| body));
+                let (certificate, tree) = certificate_header(&response);
+                let tree = decode_tree(&tree);
+                assert_eq!(certificate, *env.certified_data.borrow());
+                assert_eq!(tree.reconstruct().to_vec(), certificate);
+                assert_eq!(lookup(&tree, &[b"http_assets", path.as_bytes()]), Lookup::Found(&hash::hash_bytes(&response.body)));
+            }
+        }
+        let response = state.http_request(http_request("GET", "/"));
+        let collection: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(collection, serde_json::json!({ "name": "Domains", "symbol": "DOM", "total_supply": state.supply.to_string() }));
+
+        // Unknown paths return 404 with a proof that the path is absent
+        for path in ["/unknown", "/token/1000", "/tokens/7", "/history/1000", "/name/1"] {
+            let response = state.http_request(http_request("GET", path));
+            assert_eq!((response.status_code, response.body.len()), (404, 0), "{}", path);
+            let (certificate, tree) = certificate_header(&response);
+            let tree = decode_tree(&tree);
+            assert_eq!(tree.reconstruct().to_vec(), certificate);
+            assert_eq!(lookup(&tree, &[b"http_assets", path.as_bytes()]), Lookup::Absent, "{}", path);
+        }
+
+        // Only GET requests are served
+        for method in ["POST", "PUT", "DELETE", "get"] {
+            let response = state.http_request(http_request(method, "/name"));
+            assert_eq!((response.status_code, response.body.len()), (404, 0), "{}", method);
+            assert!(response.streaming_strategy.is_none());
+        }
+    }
+
+    #[cfg(feature = "sld4")]
+    #[test]
+    fn http_streaming() {
+        let (mut state, env) = init_state(DEFAULT_BLOCK_SIZE);
+        env.caller.set(custodian());
+        // Image of 4 MB, its token metadata is about 5.3 MB once base64 encoded
+        let image: Vec<u8> = (0..4_000_000u32).map(|i| (i % 251) as u8).collect();
+        state.mint(MintArgs {
+            to: account(2),
+            token_id: Nat::from(1),
+            metadata: HashMap::from([("sld:image".to_string(), Value::Blob(image))]),
+            memo: None,
+            created_at_time: None,
+        }).unwrap();
+
+        for path in ["/token/1", "/token/1/sld:image"] {
+            let response = state.http_request(http_request("GET", path));
+            assert_eq!(response.status_code, 200);
+            let response = candid::decode_one::<HttpResponse>(&candid::encode_one(&response).unwrap()).unwrap();
+            let (certificate, tree) = certificate_header(&response);
+            let tree = decode_tree(&tree);
+            assert_eq!(tree.reconstruct().to_vec(), certificate);
+
+            // The first response only holds the first chunk
+            let mut body = response.body.to_vec();
+            assert_eq!(body.len(), http::MAX_CHUNK_BYTES);
+            let chunk_hash = |index: usize| lookup(&tree, &[b"http_assets", http::chunk_key(path, index).as_bytes()]);
+            assert_eq!(chunk_hash(0), Lookup::Found(&hash::hash_bytes(&body)));
+            let mut token = match response.streaming_strategy {
+                Some(StreamingStrategy::Callback { callback, token }) => {
+                    assert_eq!((callback.0.principal, callback.0.method.as_str()), (env.id, "http_request_streaming_callback"));
+                    Some(token)
+                }
+                None => panic!("Body of {} is not streamed", path)
+            };
+
+            // Each chunk matches its certified hash, the last chunk doesn't return a token
+            let mut index = 1;
+            while let Some(next) = token {
+                assert_eq!(next.index, Nat::from(index));
+                let response = state.http_request_streaming_callback(next.clone());
+                let hash = hash::hash_bytes(&response.body);
+                assert_eq!(next.sha256.map(ByteBuf::into_vec), Some(hash.to_vec()));
+                assert_eq!(chunk_hash(index), Lookup::Found(&hash));
+                body.extend_from_slice(&response.body);
+                token = response.token;
+                index += 1;
+            }
+            assert_eq!(index, 3);
+            assert_eq!(lookup(&tree, &[b"http_assets", path.as_bytes()]), Lookup::Found(&hash::hash_bytes(&body)));
+            assert_eq!(Some(body), state.http_body(path).map(|(_, body)| body));
+        }
+
+        // Tokens of unknown paths and chunks out of range are rejected
+        let token = |key: &str, index: Nat| StreamingCallbackToken {
+            key: key.into(),
+            content_encoding: "identity".into(),
+            index,
+            sha256: None,
+        };
+        for token in [
+            token("/token/2", Nat::from(1)),
+            token("/name", Nat::from(0)),
+            token("/token/1", Nat::from(3)),
+            token("/token/1", Nat::from(u128::MAX) * Nat::from(u128::MAX)),
+        ] {
+            let response = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| state.http_request_streaming_callback(token)));
+            assert!(response.is_err());
+        }
+    }
+
+    #[cfg(feature = "sld4")]
+    #[test]
+    fn transfer() {
+        let (mut state, env) = init_state(DEFAULT_BLOCK_SIZE);
+        mint_token(&mut state, &env, 1, account(2));
+        let from_tx = state.tokens[&Nat::from(1)].tx_id.clone();
+
+        env.caller.set(principal(3));
+        assert!(matches!(state.transfer_from(transfer_args(account(2), account(3), 1)), Err(TransferFromError::NotApproved)));
+        assert!(matches!(state.transfer_from(transfer_args(account(3), account(4), 1)), Err(TransferFromError::NotOwner)));
+        assert!(matches!(state.transfer_from(transfer_args(account(3), account(4), 2)), Err(TransferFromError::NotFound)));
+
+        env.caller.set(principal(2));
+        assert!(matches!(state.transfer_from(transfer_args(account(2), account(2), 1)), Err(TransferFromError::GenericError(_))));
+        let tx_id = state.transfer_from(transfer_args(account(2), account(3), 1)).unwrap();
+        assert_eq!(tx(&state, 2), TxKind::Transfer { token_id: Nat::from(1), from_tx, memo: None, created_at_time: None });
+        // Without a created at time only the event has the ledger time
+        assert_eq!(block(&state, 2 / state.block_size)[2 % state.block_size].time, NOW);
+        assert_eq!(state.owner_of(&Nat::from(1)), Some(account(3)));
+        assert_eq!(state.tokens[&Nat::from(1)].tx_id, tx_id);
+        assert_eq!(state.balance_of(&account(2)), Nat::from(0));
+        assert_eq!(state.balance_of(&account(3)), Nat::from(1));
+        assert_eq!(state.total_supply(), Nat::from(1));
+    }
+
+    #[cfg(all(feature = "sld2", feature = "sld4"))]
+    #[test]
+    fn transfer_from() {
+        let (mut state, env) = init_state(DEFAULT_BLOCK_SIZE);
+        mint_token(&mut state, &env, 1, account(2));
+        env.caller.set(principal(2));
+        state.set_approval(SetApprovalArgs { from_subaccount: None, spender: principal(3), token_id: Nat::from(1), approved: true, memo: None, created_at_time: None }).unwrap();
+        let from_tx = state.tokens[&Nat::from(1)].tx_id.clone();
+
+        env.caller.set(principal(3));
+        state.transfer_from(transfer_args(account(2), account(4), 1)).unwrap();
+        assert_eq!(tx(&state, 3), TxKind::TransferFrom { token_id: Nat::from(1), from_tx, memo: None, created_at_time: None });
+        assert_eq!(state.owner_of(&Nat::from(1)), Some(account(4)));
+
+        // Approvals don't carry over to the next owner
+        assert!(state.get_approved(&Nat::from(1)).is_empty());
+        assert!(matches!(state.transfer_from(transfer_args(account(4), account(3), 1)), Err(TransferFromError::NotApproved)));
+    }
+
+    #[cfg(all(feature = "sld2", feature = "sld4"))]
+    #[test]
+    fn approval() {
+        let (mut state, env) = init_state(DEFAULT_BLOCK_SIZE);
+        mint_token(&mut state, &env, 1, account(2));
+        let args = SetApprovalArgs { from_subaccount: None, spender: principal(3), token_id: Nat::from(1), approved: true, memo: None, created_at_time: None };
+
+        env.caller.set(principal(3));
+        assert!(matches!(state.set_approval(args.clone()), Err(SetApprovalError::NotOwner)));
+        env.caller.set(principal(2));
+        assert!(matches!(state.set_approval(SetApprovalArgs { token_id: Nat::from(2), ..args.clone() }), Err(SetApprovalError::NotFound)));
+        assert!(matches!(state.approve(ApproveArgs { from_subaccount: None, spender: principal(2), token_id: Nat::from(1), approved: true, memo: None, created_at_time: None }), Err(ApproveError::NotSelf)));
+
+        let from_tx = state.tokens[&Nat::from(1)].tx_id.clone();
+        let tx_id = state.set_approval(args.clone()).unwrap();
+        assert_eq!(tx(&state, 2), TxKind::Approve { token_id: Nat::from(1), spender: principal(3), approved: true, from_tx, memo: None, created_at_time: None });
+        assert_eq!(state.get_approved(&Nat::from(1)), HashSet::from([&principal(3)]));
+        assert_eq!(state.tokens[&Nat::from(1)].tx_id, tx_id);
+
+        state.max_approvals = 1;
+        assert!(matches!(state.set_approval(SetApprovalArgs { spender: principal(4), ..args.clone() }), Err(SetApprovalError::MaxApprovals(_))));
+        state.set_approval(SetApprovalArgs { approved: false, ..args }).unwrap();
+        assert!(state.get_approved(&Nat::from(1)).is_empty());
+    }
+
+    #[cfg(all(feature = "sld2", feature = "sld4"))]
+    #[test]
+    fn operators() {
+        let (mut state, env) = init_state(DEFAULT_BLOCK_SIZE);
+        mint_token(&mut state, &env, 1, account(2));
+        mint_token(&mut state, &env, 2, account(2));
+        let args = SetApprovalForAllArgs { from_subaccount: None, operator: principal(3), approved: true, memo: None, created_at_time: None };
+
+        env.caller.set(principal(2));
+        state.set_approval_for_all(args.clone()).unwrap();
+        assert_eq!(tx(&state, 3), TxKind::ApproveForAll { operator: principal(3), approved: true, subaccount: None, memo: None, created_at_time: None });
+        assert_eq!(state.get_approved_for_all(&account(2)), HashSet::from([&principal(3)]));
+
+        state.max_approvals = 1;
+        assert!(matches!(state.set_approval_for_all(SetApprovalForAllArgs { operator: principal(4), ..args.clone() }), Err(SetApprovalForAllError::MaxApprovals(_))));
+
+        // Operators can transfer every token of the account, unlike approvals they stay after a transfer
+        env.caller.set(principal(3));
+        state.transfer_from(transfer_args(account(2), account(4), 1)).unwrap();
+        state.transfer_from(transfer_args(account(2), account(4), 2)).unwrap();
+        assert_eq!(state.balance_of(&account(4)), Nat::from(2));
+
+        env.caller.set(principal(2));
+        state.set_approval_for_all(SetApprovalForAllArgs { approved: false, ..args }).unwrap();
+        assert!(state.get_approved_for_all(&account(2)).is_empty());
+        assert!(state.operators.is_empty());
+    }
+
+    #[cfg(all(feature = "sld4", feature = "sld5"))]
+    #[test]
+    fn burn() {
+        let (mut state, env) = init_state(DEFAULT_BLOCK_SIZE);
+        mint_token(&mut state, &env, 1, account(2));
+        mint_token(&mut state, &env, 2, account(2));
+        let args = BurnArgs { token_id: Nat::from(1), memo: None, created_at_time: None };
+
+        env.caller.set(principal(3));
+        assert!(matches!(state.burn(args.clone()), Err(BurnError::NotAllowed)));
+        env.caller.set(principal(2));
+        let from_tx = state.tokens[&Nat::from(1)].tx_id.clone();
+        state.burn(args.clone()).unwrap();
+        assert_eq!(tx(&state, 3), TxKind::Burn { token_id: Nat::from(1), from_tx, memo: None, created_at_time: None });
+        assert_eq!(state.owner_of(&Nat::from(1)), Some(state.minting_account()));
+        assert_eq!(state.total_supply(), Nat::from(1));
+        assert_eq!(state.tokens(&Nat::from(0)), vec![&Nat::from(2)]);
+        assert!(matches!(state.burn(args), Err(BurnError::GenericError(err)) if err.error_code == 404));
+
+        // Burned token ids can't be minted again
+        env.caller.set(custodian());
+        assert!(matches!(state.mint(MintArgs { to: account(2), token_id: Nat::from(1), metadata: HashMap::default(), memo: None, created_at_time: None }), Err(MintError::GenericError(err)) if err.error_code == 409));
+
+        // Custodians burn their own tokens by transferring them to the minting account
+        mint_token(&mut state, &env, 3, Account::new(custodian(), None));
+        state.transfer_from(transfer_args(Account::new(custodian(), None), state.minting_account(), 3)).unwrap();
+        assert!(matches!(tx(&state, 5), TxKind::Burn { .. }));
+        assert_eq!(state.total_supply(), Nat::from(1));
+        env.caller.set(principal(2));
+        assert!(matches!(state.transfer_from(transfer_args(account(2), state.minting_account(), 2)), Err(TransferFromError::GenericError(_))));
+    }
+
+    /// Xorshift generator, random sequences can be replayed from their seed
+    #[cfg(feature = "sld4")]
+    struct Rng(u64);
+
+    #[cfg(feature = "sld4")]
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    #[cfg(feature = "sld4")]
+    #[test]
+    fn owners_match_rebuilt_owners() {
+        use crate::types::Subaccount;
+
+        for seed in 1..=20 {
+            let (mut state, env) = init_state(8);
+            let minting_account = state.minting_account();
+            let accounts = [
+                account(2),
+                account(3),
+                Account::new(principal(2), Some(Subaccount([2; 32]))),
+                Account::new(custodian(), None),
+            ];
+            let mut rng = Rng(seed);
+            for _ in 0..200 {
+                let token_id = Nat::from(rng.below(32));
+                let to = accounts[rng.below(accounts.len() as u64) as usize];
+                match (rng.below(3), state.owner_of(&token_id)) {
+                    (0, _) => {
+                        env.caller.set(custodian());
+                        let _ = state.mint(MintArgs { to, token_id, metadata: HashMap::default(), memo: None, created_at_time: None });
+                    }
+                    // Custodians burn by transferring to the minting account
+                    (1, Some(from)) if from != minting_account => {
+                        env.caller.set(from.owner);
+                        let to = if from.owner == custodian() && rng.below(2) == 0 { minting_account } else { to };
+                        let _ = state.transfer_from(TransferFromArgs { from, to, token_id, memo: None, created_at_time: None });
+                    }
+                    #[cfg(feature = "sld5")]
+                    (2, Some(from)) if from != minting_account => {
+                        env.caller.set(from.owner);
+                        state.burn(BurnArgs { token_id, memo: None, created_at_time: None }).unwrap();
+                    }
+                    _ => {}
+                }
+            }
+
+            let (owners, supply) = (state.owners.clone(), state.supply);
+            state.rebuild_owners();
+            assert_eq!(state.owners, owners, "Owners of seed {}", seed);
+            assert_eq!(state.supply, supply, "Supply of seed {}", seed);
+            let balances: usize = accounts.iter().map(|account| state.balance_of(account).0.to_usize().unwrap()).sum();
+            assert_eq!(balances, supply, "Balances of seed {}", seed);
+            assert_eq!(state.tokens.len() - supply, state.balance_of(&minting_account).0.to_usize().unwrap());
+        }
+    }
+
+    #[test]
+    fn custodians() {
+        let (mut state, env) = init_state(DEFAULT_BLOCK_SIZE);
+        env.caller.set(principal(2));
+        assert!(matches!(state.set_custodian(SetCustodianArgs { custodian: principal(2), approved: true }), Err(SetCustodiansError::NotAllowed)));
+
+        env.caller.set(custodian());
+        state.set_custodian(SetCustodianArgs { custodian: principal(2), approved: true }).unwrap();
+        assert_eq!(tx(&state, 1), TxKind::SetCustodian { custodian: principal(2), approved: true, from_tx: Nat::from(0) });
+        assert_eq!(state.custodians, HashSet::from([custodian(), principal(2)]));
+
+        state.max_custodians = 2;
+        assert!(matches!(state.set_custodian(SetCustodianArgs { custodian: principal(3), approved: true }), Err(SetCustodiansError::MaxCustodians(_))));
+        state.set_custodian(SetCustodianArgs { custodian: custodian(), approved: false }).unwrap();
+        assert_eq!(tx(&state, 2), TxKind::SetCustodian { custodian: custodian(), approved: false, from_tx: Nat::from(1) });
+        assert_eq!(state.custodians, HashSet::from([principal(2)]));
+    }
+
+    #[cfg(feature = "sld6")]
+    #[test]
+    fn metadata() {
+        let (mut state, env) = init_state(DEFAULT_BLOCK_SIZE);
+        let args = SetMetadataArgs { key: "sld1:name".into(), value: Value::Text("Names".into()) };
+        env.caller.set(principal(2));
+        assert!(matches!(state.set_metadata(args.clone()), Err(SetMetadataError::NotAllowed)));
+
+        env.caller.set(custodian());
+        state.set_metadata(args.clone()).unwrap();
+        assert_eq!(state.name, "Names");
+        assert_eq!(tx(&state, 1), TxKind::SetMetadata { key: args.key.clone(), value: args.value.clone(), from_tx: Nat::from(0) });
+        assert!(matches!(state.set_metadata(SetMetadataArgs { value: Value::Nat(Nat::from(1)), ..args.clone() }), Err(SetMetadataError::GenericError(_))));
+        assert!(matches!(state.set_metadata(SetMetadataArgs { key: "sld1:other".into(), ..args.clone() }), Err(SetMetadataError::InvalidKey)));
+        assert!(matches!(state.set_metadata(SetMetadataArgs { key: "website".into(), ..args.clone() }), Err(SetMetadataError::InvalidKey)));
+        assert!(matches!(state.remove_metadata("sld1:name".into()), Err(SetMetadataError::GenericError(_))));
+
+        state.set_metadata(SetMetadataArgs { key: "app:website".into(), value: Value::Text("https://example.com".into()) }).unwrap();
+        state.remove_metadata("app:website".into()).unwrap();
+        assert_eq!(tx(&state, 3), TxKind::RemoveMetadata { key: "app:website".into(), from_tx: Nat::from(2) });
+        assert!(!state.metadata.contains_key("app:website"));
+
+        state.freeze_metadata("sld1:name".into()).unwrap();
+        assert_eq!(tx(&state, 4), TxKind::FreezeMetadata { key: "sld1:name".into(), from_tx: Nat::from(3) });
+        assert!(matches!(state.set_metadata(args), Err(SetMetadataError::Frozen)));
+        assert_eq!(state.metadata_tx, Nat::from(4));
+    }
+
+    #[cfg(all(feature = "sld4", feature = "sld8"))]
+    #[test]
+    fn royalty() {
+        let (mut state, env) = init_state(DEFAULT_BLOCK_SIZE);
+        mint_token(&mut state, &env, 1, account(2));
+        let price = Nat::from(1_000);
+        assert_eq!(state.royalty_fee(Some(&Nat::from(1)), &price).fee, Nat::from(0));
+
+        let args = SetRoyaltyFeeArgs { token_id: None, account: account(3), fee: Nat::from(250) };
+        env.caller.set(principal(2));
+        assert!(matches!(state.set_royalty_fee(args.clone()), Err(SetRoyaltyFeeError::NotAllowed)));
+        env.caller.set(custodian());
+        assert!(matches!(state.set_royalty_fee(SetRoyaltyFeeArgs { fee: Nat::from(MAX_ROYALTY_FEE + 1), ..args.clone() }), Err(SetRoyaltyFeeError::GenericError(_))));
+        assert!(matches!(state.set_royalty_fee(SetRoyaltyFeeArgs { token_id: Some(Nat::from(2)), ..args.clone() }), Err(SetRoyaltyFeeError::GenericError(err)) if err.error_code == 404));
+
+        state.set_royalty_fee(args.clone()).unwrap();
+        assert_eq!(tx(&state, 2), TxKind::SetRoyaltyFee { token_id: None, account: account(3), fee: Nat::from(250) });
+        let royalty_fee = state.royalty_fee(Some(&Nat::from(1)), &price);
+        assert_eq!((royalty_fee.account, royalty_fee.fee), (account(3), Nat::from(25)));
+
+        // Token royalty overrides the collection royalty
+        state.set_royalty_fee(SetRoyaltyFeeArgs { token_id: Some(Nat::from(1)), account: account(4), fee: Nat::from(1_000) }).unwrap();
+        let royalty_fee = state.royalty_fee(Some(&Nat::from(1)), &price);
+        assert_eq!((royalty_fee.account, royalty_fee.fee), (account(4), Nat::from(100)));
+        assert_eq!(state.royalty_fee(None, &price).fee, Nat::from(25));
+    }
+
+    #[cfg(feature = "sld4")]
+    #[test]
+    fn dedup_window() {
+        let (mut state, env) = init_state(DEFAULT_BLOCK_SIZE);
+        mint_token(&mut state, &env, 1, account(2));
+        let args = TransferFromArgs { created_at_time: Some(NOW), ..transfer_args(account(2), account(3), 1) };
+
+        env.caller.set(principal(2));
+        let tx_id = state.transfer_from(args.clone()).unwrap();
+        assert!(matches!(state.transfer_from(args.clone()), Err(TransferFromError::GenericError(err)) if err.error_code == 409 && err.message == format!("Transaction is a duplicate of {}", tx_id)));
+
+        // Same transaction with another memo or without created at time is not a duplicate
+        env.caller.set(principal(3));
+        state.transfer_from(transfer_args(account(3), account(2), 1)).unwrap();
+        env.caller.set(principal(2));
+        let tx_id = state.transfer_from(TransferFromArgs { memo: Some([1; 32]), ..args.clone() }).unwrap();
+        assert_eq!(tx(&state, tx_id.0.to_usize().unwrap()), TxKind::Transfer { token_id: Nat::from(1), from_tx: Nat::from(3), memo: Some([1; 32]), created_at_time: Some(NOW) });
+
+        let future = NOW + state.permitted_drift + 1;
+        assert!(matches!(state.transfer_from(TransferFromArgs { created_at_time: Some(future), ..args.clone() }), Err(TransferFromError::GenericError(err)) if err.error_code == 400));
+        // SLD-1 defines errors for the transaction window
+        assert!(matches!(state.transfer(TransferFromArgs { created_at_time: Some(future), ..args.clone() }), Err(TransferError::CreatedInFuture { ledger_time: NOW })));
+
+        // Transactions that fall out of the window are rejected and no longer remembered
+        let now = NOW + state.tx_window + state.permitted_drift + 1;
+        env.time.set(now);
+        assert!(matches!(state.transfer_from(args.clone()), Err(TransferFromError::GenericError(err)) if err.error_code == 400));
+        assert!(matches!(state.transfer(args), Err(TransferError::TooOld)));
+        env.caller.set(principal(3));
+        state.transfer_from(TransferFromArgs { created_at_time: Some(now), ..transfer_args(account(3), account(2), 1) }).unwrap();
+        assert_eq!(state.tx_hashes.len(), 1);
+    }
+
+    #[test]
+    fn stable_state_round_trip() {
+        // Populating is deterministic, so the expected state is populated a second time
+        let (expected, _) = populated_state();
+        let (state, env) = populated_state();
+        let bytes = candid::encode_one(StableState::from(state)).unwrap();
+        let restored = State::from_stable(candid::decode_one(&bytes).unwrap(), Box::new(env.clone()));
+        assert_restored(&expected, &restored);
+    }
+
+    #[test]
+    fn save_and_restore() {
+        let (expected, _) = populated_state();
+        let (state, env) = populated_state();
+        state.save().unwrap();
+        let mut restored = State::restore(Box::new(env.clone())).unwrap();
+        assert_restored(&expected, &restored);
+
+        // Log continues where it was left before the upgrade
+        env.caller.set(custodian());
+        let tx_id = restored.set_custodian(SetCustodianArgs { custodian: principal(8), approved: true }).unwrap();
+        assert_eq!(tx_id, expected.tx_total);
+        assert_eq!(tx(&restored, tx_id.0.to_usize().unwrap()), TxKind::SetCustodian { custodian: principal(8), approved: true, from_tx: expected.custodians_tx.clone() });
+    }
+
+    #[test]
+    fn restore_without_snapshot() {
+        let env = Rc::new(MockEnvironment::default());
+        let state = State::restore(Box::new(env.clone())).unwrap();
+        assert_eq!((state.tx_total.clone(), state.total_supply()), (Nat::from(0), Nat::from(0)));
+        assert!(state.hash_tree.get(b"/name").is_some());
+        assert!(!env.certified_data.borrow().is_empty());
+
+        // Memory that has been grown by the canister but never saved to has an empty header
+        env.memory().grow(1).unwrap();
+        let mut state = State::restore(Box::new(env.clone())).unwrap();
+        state.upgrade(UpgradeArgs { max_supply: Some(Nat::from(10)), ..Default::default() }).unwrap();
+        assert_eq!(state.max_supply, Some(10));
+    }
+
+    /// Every transaction is chained to the one before it and the tip is the hash of the last one
+    fn assert_chained(state: &State) {
+        let tx_total = state.tx_total.0.to_usize().unwrap();
+        let events: Vec<Event> = (0..tx_total).map(|tx_id| block(state, tx_id / state.block_size)[tx_id % state.block_size].clone()).collect();
+        assert_eq!(events[0].details.get(hash::PARENT_HASH_KEY), None);
+        for pair in events.windows(2) {
+            assert_eq!(pair[1].details.get(hash::PARENT_HASH_KEY), Some(&Value::Blob(hash::hash_event(&pair[0]).to_vec())));
+        }
+        assert_eq!(state.tip_hash, Some(hash::hash_event(&events[tx_total - 1])));
+    }
+
+    #[test]
+    fn parent_hashes() {
+        let (state, env) = populated_state();
+        assert_chained(&state);
+
+        // Chain continues from the restored tip, also across the block that was current
+        state.save().unwrap();
+        let mut restored = State::restore(Box::new(env.clone())).unwrap();
+        env.caller.set(custodian());
+        for id in 11..16 {
+            restored.set_custodian(SetCustodianArgs { custodian: principal(id), approved: true }).unwrap();
+        }
+        assert_chained(&restored);
+    }
+
+    #[cfg(feature = "sld3")]
+    #[test]
+    fn tip_certificate() {
+        fn assert_tip(state: &State, env: &MockEnvironment) {
+            let certificate = state.tip_certificate().unwrap();
+            assert_eq!(certificate.certificate.to_vec(), *env.certified_data.borrow());
+            let tree: serde_cbor::Value = serde_cbor::from_slice(&certificate.hash_tree).unwrap();
+            let tree = decode_tree(&tree);
+            assert_eq!(tree.reconstruct().to_vec(), certificate.certificate.to_vec());
+
+            // Tip is the index and hash of the last transaction as ICRC-3 block, the http assets are pruned
+            let last_tx_id = state.tx_total.0.to_usize().unwrap() - 1;
+            let mut last_block_index = vec![];
+            Nat::from(last_tx_id).encode(&mut last_block_index).unwrap();
+            let last_block = icrc3::block(&block(state, last_tx_id / state.block_size)[last_tx_id % state.block_size]);
+            assert_eq!(lookup(&tree, &[b"last_block_index"]), Lookup::Found(&last_block_index));
+            assert_eq!(lookup(&tree, &[b"last_block_hash"]), Lookup::Found(&hash::hash_value(&last_block)));
+            assert_eq!(lookup(&tree, &[b"http_assets", b"/name"]), Lookup::Unknown);
+
+            // Certificates of http responses prune the tip instead
+            let response = state.http_request(http_request("GET", "/name"));
+            let (_, tree) = certificate_header(&response);
+            assert_eq!(lookup(&decode_tree(&tree), &[b"last_block_hash"]), Lookup::Unknown);
+        }
+
+        let (mut state, env) = populated_state();
+        assert_tip(&state, &env);
+        env.caller.set(custodian());
+        state.set_custodian(SetCustodianArgs { custodian: principal(11), approved: true }).unwrap();
+        assert_tip(&state, &env);
+    }
+
+    #[cfg(feature = "sld4")]
+    #[test]
+    fn dedup_mint_metadata() {
+        let (mut state, env) = init_state(DEFAULT_BLOCK_SIZE);
+        env.caller.set(custodian());
+
+        // Entries are inserted in opposite order, nested maps included
+        let entries: Vec<(String, Value)> = (0..16).map(|index| (format!("app:{}", index), Value::Map(HashMap::from([
+            ("a".to_string(), Value::Nat(Nat::from(index))),
+            ("b".to_string(), Value::Text(index.to_string())),
+        ])))).collect();
+        let args = MintArgs { to: account(2), token_id: Nat::from(1), metadata: entries.iter().cloned().collect(), memo: None, created_at_time: Some(NOW) };
+        let tx_id = state.mint(args.clone()).unwrap();
+        let metadata = entries.into_iter().rev().collect();
+        assert!(matches!(state.mint(MintArgs { metadata, ..args.clone() }), Err(MintError::GenericError(err)) if err.message == format!("Transaction is a duplicate of {}", tx_id)));
+
+        let metadata = HashMap::from([("app:0".to_string(), Value::Nat(Nat::from(0)))]);
+        assert!(matches!(state.mint(MintArgs { metadata, ..args }), Err(MintError::GenericError(err)) if err.message == "Token already exists"));
+    }
+
+    #[test]
+    fn block_sealing() {
+        let (mut state, env) = init_state(2);
+        env.caller.set(custodian());
+        for id in 2..7 {
+            state.set_custodian(SetCustodianArgs { custodian: principal(id), approved: true }).unwrap();
+        }
+
+        // Six transactions fill three blocks that are sealed one after the other in memory
+        assert_eq!(state.tx_total, Nat::from(6));
+        assert_eq!(state.block_indexes.len(), 3);
+        assert!(state.current_block.is_empty());
+        assert!(state.block_indexes[0] > HEADER_BYTES);
+        assert!(state.block_indexes.windows(2).all(|ends| ends[0] < ends[1]));
+        assert_eq!(state.stable_offset(), state.block_indexes[2]);
+        assert!(env.memory.0.borrow().len() >= state.stable_offset());
+
+        let custodians: Vec<TxKind> = (0..6).map(|tx_id| tx(&state, tx_id)).collect();
+        assert_eq!(custodians[0], TxKind::SetCustodian { custodian: custodian(), approved: true, from_tx: Nat::from(0) });
+        for (tx_id, kind) in custodians.iter().enumerate().skip(1) {
+            assert_eq!(kind, &TxKind::SetCustodian { custodian: principal(tx_id as u8 + 1), approved: true, from_tx: Nat::from(tx_id - 1) });
+        }
+
+        // Sealed blocks are still chained and served over http
+        let phash = hash::hash_event(&block(&state, 0)[1]);
+        assert_eq!(block(&state, 1)[0].details.get(hash::PARENT_HASH_KEY), Some(&Value::Blob(phash.to_vec())));
+        assert!(state.read_block(Nat::from(3)).is_some());
+        assert!(state.read_block(Nat::from(4)).is_none());
+
+        // Hashes of sealed blocks are kept, they're the hashes of the bodies that are served
+        assert_eq!(state.history_hashes.len(), 3);
+        for (block_id, hashes) in state.history_hashes.iter().enumerate() {
+            let path = format!("/history/{}", block_id);
+            let (_, body) = state.http_body(&path).unwrap();
+            assert_eq!(hashes, &http::body_hashes(&body));
+            assert_eq!(state.hash_tree.get(path.as_bytes()), Some(&hashes.0));
+        }
+    }
+
+    /// Write the given number of transactions that approve and revoke a custodian in turns
+    #[cfg(feature = "sld3")]
+    fn custodian_txs(state: &mut State, env: &MockEnvironment, count: usize) {
+        env.caller.set(custodian());
+        for i in 0..count {
+            state.set_custodian(SetCustodianArgs { custodian: principal(2), approved: i & 1 == 0 }).unwrap();
+        }
+    }
+
+    #[cfg(feature = "sld3")]
+    fn block_ids(result: &GetBlocksResult) -> Vec<usize> {
+        result.blocks.iter().map(|block| block.id.0.to_usize().unwrap()).collect()
+    }
+
+    /// Ranges that should be fetched from each bucket in the order that they were returned
+    #[cfg(feature = "sld3")]
+    fn archived_ranges(result: &GetBlocksResult) -> Vec<(Principal, Vec<(usize, usize)>)> {
+        result.archived_blocks.iter().map(|archived| {
+            assert_eq!(archived.callback.0.method, "icrc3_get_blocks");
+            let ranges = archived.args.iter().map(|range| (range.start.0.to_usize().unwrap(), range.length.0.to_usize().unwrap()));
+            (archived.callback.0.principal, ranges.collect())
+        }).collect()
+    }
+
+    #[cfg(feature = "sld3")]
+    fn block_range(start: usize, length: usize) -> BlockRange {
+        BlockRange { start: Nat::from(start), length: Nat::from(length) }
+    }
+
+    #[cfg(feature = "sld3")]
+    #[test]
+    fn get_blocks() {
+        // Eleven transactions in five sealed blocks and the current block
+        let (mut state, env) = init_state(2);
+        custodian_txs(&mut state, &env, 10);
+        assert_eq!(state.block_indexes.len(), 5);
+
+        // Ranges are returned in the order that they're given and cut off at the end of the log
+        let result = state.get_blocks(vec![block_range(1, 2), block_range(7, 3), block_range(9, 100), block_range(20, 1)]);
+        assert_eq!(result.log_length, Nat::from(11));
+        assert_eq!(block_ids(&result), vec![1, 2, 7, 8, 9, 9, 10]);
+        assert!(result.archived_blocks.is_empty());
+        for block in &result.blocks {
+            let tx_id = block.id.0.to_usize().unwrap();
+            assert_eq!(block.block, icrc3::block(&self::block(&state, tx_id / 2)[tx_id % 2]));
+        }
+
+        // Offloaded transactions are passed on to the bucket that holds them
+        let (bucket_a, bucket_b) = (principal(50), principal(51));
+        state.offload_block(bucket_a, 0);
+        state.offload_block(bucket_a, 1);
+        state.offload_block(bucket_b, 2);
+        let result = state.get_blocks(vec![block_range(1, 8)]);
+        assert_eq!(archived_ranges(&result), vec![(bucket_a, vec![(1, 3)]), (bucket_b, vec![(4, 2)])]);
+        assert_eq!(block_ids(&result), vec![6, 7, 8]);
+        let decoded = candid::decode_one::<GetBlocksResult>(&candid::encode_one(&result).unwrap()).unwrap();
+        assert_eq!(archived_ranges(&decoded), archived_ranges(&result));
+        let result = state.get_blocks(vec![block_range(5, 2), block_range(0, 1), block_range(2, 1), block_range(3, 3)]);
+        assert_eq!(archived_ranges(&result), vec![
+            (bucket_b, vec![(5, 1), (4, 2)]),
+            (bucket_a, vec![(0, 1), (2, 1), (3, 1)]),
+        ]);
+        assert_eq!(block_ids(&result), vec![6]);
+    }
+
+    #[cfg(feature = "sld3")]
+    #[test]
+    fn get_blocks_limit() {
+        let (mut state, env) = init_state(10);
+        custodian_txs(&mut state, &env, MAX_BLOCKS_PER_REQUEST + 20);
+        state.offload_block(principal(50), 0);
+
+        // Ranges after the limit has been reached are left out, including offloaded ranges
+        let result = state.get_blocks(vec![block_range(10, 1_500), block_range(1_510, 1_000), block_range(0, 5)]);
+        assert_eq!(block_ids(&result), (10..10 + MAX_BLOCKS_PER_REQUEST).collect::<Vec<_>>());
+        assert!(result.archived_blocks.is_empty());
+
+        // Offloaded ranges before the limit are still returned
+        let result = state.get_blocks(vec![block_range(0, 5), block_range(10, MAX_BLOCKS_PER_REQUEST + 10), block_range(5, 5)]);
+        assert_eq!(archived_ranges(&result), vec![(principal(50), vec![(0, 5)])]);
+        assert_eq!(block_ids(&result), (10..10 + MAX_BLOCKS_PER_REQUEST).collect::<Vec<_>>());
+    }
+
+    #[cfg(feature = "sld3")]
+    #[test]
+    fn offload_blocks() {
+        // Nine transactions in four sealed blocks and the current block
+        let (mut state, env) = init_state(2);
+        custodian_txs(&mut state, &env, 8);
+        let hashes = |block: &[Event]| block.iter().map(hash::hash_event).collect::<Vec<_>>();
+        let sealed: Vec<_> = (0..4).map(|block_id| hashes(&block(&state, block_id))).collect();
+
+        // Mock bucket that receives the sealed blocks in order, like `sld3_offload_blocks` does
+        let bucket = principal(50);
+        let mut bucket_blocks = vec![];
+        while let Some((block_id, events)) = state.next_offload_block() {
+            assert_eq!(block_id, bucket_blocks.len());
+            bucket_blocks.push(hashes(&events));
+            state.offload_block(bucket, block_id);
+            assert!(matches!(state.read_block(Nat::from(block_id)), Some(BlockOrBucket::Bucket(principal)) if principal == bucket));
+            assert!(matches!(state.read_block(Nat::from(block_id + 1)), Some(BlockOrBucket::Block(_))));
+        }
+        assert_eq!(bucket_blocks, sealed);
+        assert_eq!((state.offloaded_blocks(), state.buckets.clone()), (4, vec![(bucket, 4)]));
+
+        // Offloaded transactions are answered with their bucket, the current block is still served
+        for tx_id in 0..8 {
+            assert!(matches!(state.read_tx(Nat::from(tx_id)), Some(EventOrBucket::Bucket(principal)) if principal == bucket));
+        }
+        assert!(matches!(state.read_tx(Nat::from(8)), Some(EventOrBucket::Event(_))));
+        assert!(state.read_tx(Nat::from(9)).is_none());
+        assert!(state.http_body("/history/0").is_none());
+        assert!(state.hash_tree.get(b"/history/3").is_none());
+        assert!(state.hash_tree.get(b"/history/4").is_some());
+
+        // Blocks sealed later on continue in the next bucket, also after an upgrade
+        custodian_txs(&mut state, &env, 1);
+        state.save().unwrap();
+        let mut state = State::restore(Box::new(env.clone())).unwrap();
+        let (block_id, events) = state.next_offload_block().unwrap();
+        assert_eq!((block_id, events.len()), (4, 2));
+        state.offload_block(principal(51), block_id);
+        assert!(state.next_offload_block().is_none());
+        assert_eq!(state.buckets, vec![(bucket, 4), (principal(51), 5)]);
+        assert!(matches!(state.read_tx(Nat::from(0)), Some(EventOrBucket::Bucket(principal)) if principal == bucket));
+        assert!(matches!(state.read_tx(Nat::from(9)), Some(EventOrBucket::Bucket(next_bucket)) if next_bucket == principal(51)));
+        assert!(matches!(state.read_block(Nat::from(5)), Some(BlockOrBucket::Block(block)) if block.is_empty()));
+
+        // Only custodians can offload and only a single offload runs at the same time
+        assert!(matches!(state.start_offload(principal(3)), Err(OffloadBlocksError::NotAllowed)));
+        state.start_offload(custodian()).unwrap();
+        assert!(matches!(state.start_offload(custodian()), Err(OffloadBlocksError::TemporarilyUnavailable)));
+        state.end_offload();
+        state.start_offload(custodian()).unwrap();
+    }
+
+    #[cfg(feature = "sld3")]
+    #[test]
+    fn get_archives() {
+        let (mut state, env) = init_state(2);
+        custodian_txs(&mut state, &env, 10);
+        assert!(state.get_archives(None).is_empty());
+
+        let (bucket_a, bucket_b) = (principal(50), principal(51));
+        for (bucket, block_id) in [(bucket_a, 0), (bucket_a, 1), (bucket_b, 2), (bucket_b, 3)] {
+            state.offload_block(bucket, block_id);
+        }
+        let archives = |from: Option<Principal>| state.get_archives(from)
+            .into_iter()
+            .map(|archive| (archive.canister_id, archive.start.0.to_usize().unwrap(), archive.end.0.to_usize().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(archives(None), vec![(bucket_a, 0, 3), (bucket_b, 4, 7)]);
+        assert_eq!(archives(Some(bucket_a)), vec![(bucket_b, 4, 7)]);
+        assert!(archives(Some(bucket_b)).is_empty());
+        assert!(archives(Some(principal(52))).is_empty());
+    }
+
+    #[cfg(all(feature = "sld4", feature = "sld5"))]
+    #[test]
+    fn token_pages_certified() {
+        let (mut state, env) = init_state(DEFAULT_BLOCK_SIZE);
+        for token_id in [1, 2, 1_500] {
+            mint_token(&mut state, &env, token_id, account(2));
+        }
+        let body = |state: &State, path: &str| state.http_body(path).map(|(_, body)| String::from_utf8(body).unwrap());
+        let certified = |state: &State, path: &str| state.hash_tree.get(path.as_bytes()).copied();
+        assert_eq!(body(&state, "/tokens").unwrap(), r#"["0","1"]"#);
+        assert_eq!(body(&state, "/tokens/0").unwrap(), r#"["1","2"]"#);
+        assert_eq!(body(&state, "/tokens/1").unwrap(), r#"["1500"]"#);
+        for path in ["/tokens", "/tokens/0", "/tokens/1"] {
+            assert_eq!(certified(&state, path), Some(http::body_hashes(body(&state, path).unwrap().as_bytes()).0));
+        }
+
+        // Burning a token only changes its own page as long as the page holds other tokens
+        let tokens_hash = certified(&state, "/tokens");
+        let page_hash = certified(&state, "/tokens/1");
+        env.caller.set(principal(2));
+        state.burn(BurnArgs { token_id: Nat::from(2), memo: None, created_at_time: None }).unwrap();
+        assert_eq!((certified(&state, "/tokens"), certified(&state, "/tokens/1")), (tokens_hash, page_hash));
+        assert_eq!(certified(&state, "/tokens/0"), Some(http::body_hashes(br#"["1"]"#).0));
+
+        // Pages without tokens are no longer served
+        state.burn(BurnArgs { token_id: Nat::from(1_500), memo: None, created_at_time: None }).unwrap();
+        assert_eq!(certified(&state, "/tokens/1"), None);
+        assert_eq!(certified(&state, "/tokens"), Some(http::body_hashes(br#"["0"]"#).0));
+    }
+}
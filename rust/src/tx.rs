@@ -0,0 +1,567 @@
+//! Typed transactions of the log, each kind of transaction is written to the log as an
+//! [Event] with its operation and details, and can be read back from that event without loss.
+//!
+//! Principals and accounts are stored as text, booleans as a nat that is either 0 or 1.
+//!
+//! Every kind of transaction is read whatever standards are enabled, so a log written by a
+//! build with other features can still be read back. Only [OPERATIONS] follows the features.
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+
+use candid::Nat;
+use ic_cdk::export::Principal;
+use num_traits::ToPrimitive;
+
+use crate::hash::PARENT_HASH_KEY;
+use crate::types::{Account, Event, Subaccount, TokenId, Value};
+
+const MINT: &str = "sld1:mint";
+const TRANSFER: &str = "sld1:transfer";
+const TRANSFER_FROM: &str = "sld2:transfer_from";
+const BURN: &str = "sld5:burn";
+const APPROVE: &str = "sld2:approve";
+const APPROVE_FOR_ALL: &str = "sld2:approve_for_all";
+const SET_CUSTODIAN: &str = "sld6:set_custodian";
+const SET_METADATA: &str = "sld6:set_metadata";
+const REMOVE_METADATA: &str = "sld6:remove_metadata";
+const FREEZE_METADATA: &str = "sld6:freeze_metadata";
+const SET_ROYALTY_FEE: &str = "sld8:set_royalty_fee";
+
+/// Operations of the transactions that the ledger writes to the log, which are the ICRC-3 block types
+pub const OPERATIONS: &[&str] = &[
+    TRANSFER,
+    #[cfg(feature = "sld4")]
+    MINT,
+    #[cfg(feature = "sld2")]
+    TRANSFER_FROM,
+    #[cfg(feature = "sld2")]
+    APPROVE,
+    #[cfg(feature = "sld2")]
+    APPROVE_FOR_ALL,
+    #[cfg(feature = "sld5")]
+    BURN,
+    SET_CUSTODIAN,
+    #[cfg(feature = "sld8")]
+    SET_ROYALTY_FEE,
+    #[cfg(feature = "sld6")]
+    SET_METADATA,
+    #[cfg(feature = "sld6")]
+    REMOVE_METADATA,
+    #[cfg(feature = "sld6")]
+    FREEZE_METADATA,
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TxKind {
+    Mint {
+        token_id: TokenId,
+        to: Account,
+        memo: Option<[u8; 32]>,
+        created_at_time: Option<u64>,
+    },
+    /// The created at time is the one given by the caller, the ledger time is the time of the event
+    Transfer {
+        token_id: TokenId,
+        from_tx: Nat,
+        memo: Option<[u8; 32]>,
+        created_at_time: Option<u64>,
+    },
+    TransferFrom {
+        token_id: TokenId,
+        from_tx: Nat,
+        memo: Option<[u8; 32]>,
+        created_at_time: Option<u64>,
+    },
+    /// Both burns and transfers to the minting account
+    Burn {
+        token_id: TokenId,
+        from_tx: Nat,
+        memo: Option<[u8; 32]>,
+        created_at_time: Option<u64>,
+    },
+    Approve {
+        token_id: TokenId,
+        spender: Principal,
+        approved: bool,
+        from_tx: Nat,
+        memo: Option<[u8; 32]>,
+        created_at_time: Option<u64>,
+    },
+    ApproveForAll {
+        operator: Principal,
+        approved: bool,
+        subaccount: Option<Subaccount>,
+        memo: Option<[u8; 32]>,
+        created_at_time: Option<u64>,
+    },
+    SetCustodian {
+        custodian: Principal,
+        approved: bool,
+        from_tx: Nat,
+    },
+    SetMetadata {
+        key: String,
+        value: Value,
+        from_tx: Nat,
+    },
+    RemoveMetadata {
+        key: String,
+        from_tx: Nat,
+    },
+    FreezeMetadata {
+        key: String,
+        from_tx: Nat,
+    },
+    SetRoyaltyFee {
+        token_id: Option<TokenId>,
+        account: Account,
+        fee: Nat,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseEventError {
+    /// Operation is not one of the transaction kinds
+    UnknownOperation(String),
+    /// Detail is required by the operation but missing
+    MissingDetail(String),
+    /// Detail doesn't hold the expected type of value
+    InvalidDetail(String),
+    /// Detail is not part of the operation
+    UnexpectedDetail(String),
+}
+
+impl fmt::Display for ParseEventError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseEventError::UnknownOperation(operation) => write!(f, "Operation {} is unknown", operation),
+            ParseEventError::MissingDetail(key) => write!(f, "Detail {} is missing", key),
+            ParseEventError::InvalidDetail(key) => write!(f, "Detail {} is invalid", key),
+            ParseEventError::UnexpectedDetail(key) => write!(f, "Detail {} is unexpected", key),
+        }
+    }
+}
+
+impl TxKind {
+    pub fn operation(&self) -> &'static str {
+        match self {
+            TxKind::Mint { .. } => MINT,
+            TxKind::Transfer { .. } => TRANSFER,
+            TxKind::TransferFrom { .. } => TRANSFER_FROM,
+            TxKind::Burn { .. } => BURN,
+            TxKind::Approve { .. } => APPROVE,
+            TxKind::ApproveForAll { .. } => APPROVE_FOR_ALL,
+            TxKind::SetCustodian { .. } => SET_CUSTODIAN,
+            TxKind::SetMetadata { .. } => SET_METADATA,
+            TxKind::RemoveMetadata { .. } => REMOVE_METADATA,
+            TxKind::FreezeMetadata { .. } => FREEZE_METADATA,
+            TxKind::SetRoyaltyFee { .. } => SET_ROYALTY_FEE,
+        }
+    }
+
+    pub fn into_event(self, caller: Principal, time: u64) -> Event {
+        let operation = self.operation().into();
+        let mut details = HashMap::new();
+        let (memo, created_at_time) = match self {
+            TxKind::Mint { token_id, to, memo, created_at_time } => {
+                details.insert("token_id".into(), Value::Nat(token_id));
+                details.insert("to".into(), Value::Text(to.to_string()));
+                (memo, created_at_time)
+            }
+            TxKind::Transfer { token_id, from_tx, memo, created_at_time } => {
+                details.insert("token_id".into(), Value::Nat(token_id));
+                details.insert("from_tx".into(), Value::Nat(from_tx));
+                (memo, created_at_time)
+            }
+            TxKind::TransferFrom { token_id, from_tx, memo, created_at_time } => {
+                details.insert("token_id".into(), Value::Nat(token_id));
+                details.insert("from_tx".into(), Value::Nat(from_tx));
+                (memo, created_at_time)
+            }
+            TxKind::Burn { token_id, from_tx, memo, created_at_time } => {
+                details.insert("token_id".into(), Value::Nat(token_id));
+                details.insert("from_tx".into(), Value::Nat(from_tx));
+                (memo, created_at_time)
+            }
+            TxKind::Approve { token_id, spender, approved, from_tx, memo, created_at_time } => {
+                details.insert("token_id".into(), Value::Nat(token_id));
+                details.insert("spender".into(), Value::Text(spender.to_string()));
+                details.insert("approved".into(), bool_value(approved));
+                details.insert("from_tx".into(), Value::Nat(from_tx));
+                (memo, created_at_time)
+            }
+            TxKind::ApproveForAll { operator, approved, subaccount, memo, created_at_time } => {
+                details.insert("operator".into(), Value::Text(operator.to_string()));
+                details.insert("approved".into(), bool_value(approved));
+                if let Some(subaccount) = subaccount {
+                    details.insert("subaccount".into(), Value::Blob(Vec::from(subaccount.0)));
+                }
+                (memo, created_at_time)
+            }
+            TxKind::SetCustodian { custodian, approved, from_tx } => {
+                details.insert("custodian".into(), Value::Text(custodian.to_string()));
+                details.insert("approved".into(), bool_value(approved));
+                details.insert("from_tx".into(), Value::Nat(from_tx));
+                (None, None)
+            }
+            TxKind::SetMetadata { key, value, from_tx } => {
+                details.insert("key".into(), Value::Text(key));
+                details.insert("value".into(), value);
+                details.insert("from_tx".into(), Value::Nat(from_tx));
+                (None, None)
+            }
+            TxKind::RemoveMetadata { key, from_tx } | TxKind::FreezeMetadata { key, from_tx } => {
+                details.insert("key".into(), Value::Text(key));
+                details.insert("from_tx".into(), Value::Nat(from_tx));
+                (None, None)
+            }
+            TxKind::SetRoyaltyFee { token_id, account, fee } => {
+                if let Some(token_id) = token_id {
+                    details.insert("token_id".into(), Value::Nat(token_id));
+                }
+                details.insert("account".into(), Value::Text(account.to_string()));
+                details.insert("fee".into(), Value::Nat(fee));
+                (None, None)
+            }
+        };
+        if let Some(memo) = memo {
+            details.insert("memo".into(), Value::Blob(Vec::from(memo)));
+        }
+        if let Some(created_at_time) = created_at_time {
+            details.insert("time".into(), Value::Nat(Nat::from(created_at_time)));
+        }
+        Event {
+            caller,
+            operation,
+            time,
+            details,
+        }
+    }
+
+    /// Read the transaction back from its event, the parent hash is not part of
+    /// the transaction itself and is ignored. Any other unknown detail is rejected.
+    pub fn try_from_event(event: &Event) -> Result<Self, ParseEventError> {
+        let mut details = Details(event.details
+            .iter()
+            .filter(|(key, _)| key.as_str() != PARENT_HASH_KEY)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect());
+        let kind = match event.operation.as_str() {
+            MINT => TxKind::Mint {
+                token_id: details.nat("token_id")?,
+                to: details.account("to")?,
+                memo: details.memo()?,
+                created_at_time: details.created_at_time()?,
+            },
+            TRANSFER => TxKind::Transfer {
+                token_id: details.nat("token_id")?,
+                from_tx: details.nat("from_tx")?,
+                memo: details.memo()?,
+                created_at_time: details.created_at_time()?,
+            },
+            TRANSFER_FROM => TxKind::TransferFrom {
+                token_id: details.nat("token_id")?,
+                from_tx: details.nat("from_tx")?,
+                memo: details.memo()?,
+                created_at_time: details.created_at_time()?,
+            },
+            BURN => TxKind::Burn {
+                token_id: details.nat("token_id")?,
+                from_tx: details.nat("from_tx")?,
+                memo: details.memo()?,
+                created_at_time: details.created_at_time()?,
+            },
+            APPROVE => TxKind::Approve {
+                token_id: details.nat("token_id")?,
+                spender: details.principal("spender")?,
+                approved: details.bool("approved")?,
+                from_tx: details.nat("from_tx")?,
+                memo: details.memo()?,
+                created_at_time: details.created_at_time()?,
+            },
+            APPROVE_FOR_ALL => TxKind::ApproveForAll {
+                operator: details.principal("operator")?,
+                approved: details.bool("approved")?,
+                subaccount: details.subaccount()?,
+                memo: details.memo()?,
+                created_at_time: details.created_at_time()?,
+            },
+            SET_CUSTODIAN => TxKind::SetCustodian {
+                custodian: details.principal("custodian")?,
+                approved: details.bool("approved")?,
+                from_tx: details.nat("from_tx")?,
+            },
+            SET_METADATA => TxKind::SetMetadata {
+                key: details.text("key")?,
+                value: details.take("value")?,
+                from_tx: details.nat("from_tx")?,
+            },
+            REMOVE_METADATA => TxKind::RemoveMetadata {
+                key: details.text("key")?,
+                from_tx: details.nat("from_tx")?,
+            },
+            FREEZE_METADATA => TxKind::FreezeMetadata {
+                key: details.text("key")?,
+                from_tx: details.nat("from_tx")?,
+            },
+            SET_ROYALTY_FEE => TxKind::SetRoyaltyFee {
+                token_id: details.optional("token_id", |value| value.as_nat().cloned())?,
+                account: details.account("account")?,
+                fee: details.nat("fee")?,
+            },
+            operation => return Err(ParseEventError::UnknownOperation(operation.into()))
+        };
+        match details.0.keys().next() {
+            Some(key) => Err(ParseEventError::UnexpectedDetail(key.clone())),
+            None => Ok(kind)
+        }
+    }
+}
+
+fn bool_value(value: bool) -> Value {
+    Value::Nat(Nat::from(if value { 1 } else { 0 }))
+}
+
+/// Details that have not been read yet, each detail is taken out once it has been read
+struct Details(HashMap<String, Value>);
+
+impl Details {
+    fn take(&mut self, key: &str) -> Result<Value, ParseEventError> {
+        self.0.remove(key).ok_or_else(|| ParseEventError::MissingDetail(key.into()))
+    }
+
+    fn required<T>(&mut self, key: &str, parse: impl FnOnce(&Value) -> Option<T>) -> Result<T, ParseEventError> {
+        parse(&self.take(key)?).ok_or_else(|| ParseEventError::InvalidDetail(key.into()))
+    }
+
+    fn optional<T>(&mut self, key: &str, parse: impl FnOnce(&Value) -> Option<T>) -> Result<Option<T>, ParseEventError> {
+        match self.0.remove(key) {
+            Some(value) => parse(&value).map(Some).ok_or_else(|| ParseEventError::InvalidDetail(key.into())),
+            None => Ok(None)
+        }
+    }
+
+    fn nat(&mut self, key: &str) -> Result<Nat, ParseEventError> {
+        self.required(key, |value| value.as_nat().cloned())
+    }
+
+    fn text(&mut self, key: &str) -> Result<String, ParseEventError> {
+        self.required(key, |value| value.as_text().map(String::from))
+    }
+
+    fn principal(&mut self, key: &str) -> Result<Principal, ParseEventError> {
+        self.required(key, Value::as_principal)
+    }
+
+    fn account(&mut self, key: &str) -> Result<Account, ParseEventError> {
+        self.required(key, Value::as_account)
+    }
+
+    fn bool(&mut self, key: &str) -> Result<bool, ParseEventError> {
+        self.required(key, |value| match value.as_nat()?.0.to_u8()? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None
+        })
+    }
+
+    fn memo(&mut self) -> Result<Option<[u8; 32]>, ParseEventError> {
+        self.optional("memo", |value| value.as_blob()?.try_into().ok())
+    }
+
+    fn created_at_time(&mut self) -> Result<Option<u64>, ParseEventError> {
+        self.optional("time", |value| value.as_nat()?.0.to_u64())
+    }
+
+    fn subaccount(&mut self) -> Result<Option<Subaccount>, ParseEventError> {
+        self.optional("subaccount", |value| value.as_blob()?.try_into().ok().map(Subaccount))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Transactions are generated from a fixed seed, so failures can be reproduced
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn bool(&mut self) -> bool {
+            self.next() & 1 == 0
+        }
+
+        fn nat(&mut self) -> Nat {
+            Nat::from(self.next()) * Nat::from(self.next() % 1000)
+        }
+
+        fn bytes(&mut self) -> [u8; 32] {
+            let mut bytes = [0; 32];
+            bytes.iter_mut().for_each(|byte| *byte = self.next() as u8);
+            bytes
+        }
+
+        fn option<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> Option<T> {
+            if self.bool() { Some(f(self)) } else { None }
+        }
+
+        /// Self-authenticating principal, the class that user principals are in
+        fn principal(&mut self) -> Principal {
+            let mut bytes = self.bytes();
+            bytes[28] = 2;
+            Principal::from_slice(&bytes[..29])
+        }
+
+        fn account(&mut self) -> Account {
+            Account::new(self.principal(), self.option(|rng| Subaccount(rng.bytes())))
+        }
+
+        fn value(&mut self) -> Value {
+            match self.next() % 4 {
+                0 => Value::Nat(self.nat()),
+                1 => Value::Text(format!("{}", self.next())),
+                2 => Value::Blob(Vec::from(self.bytes())),
+                _ => Value::Array(vec![self.value(), self.value()]),
+            }
+        }
+
+        /// Transaction of each kind, in the order of the variants
+        fn tx_kinds(&mut self) -> Vec<TxKind> {
+            vec![
+                TxKind::Mint {
+                    token_id: self.nat(),
+                    to: self.account(),
+                    memo: self.option(Rng::bytes),
+                    created_at_time: self.option(Rng::next),
+                },
+                TxKind::Transfer {
+                    token_id: self.nat(),
+                    from_tx: self.nat(),
+                    memo: self.option(Rng::bytes),
+                    created_at_time: self.option(Rng::next),
+                },
+                TxKind::TransferFrom {
+                    token_id: self.nat(),
+                    from_tx: self.nat(),
+                    memo: self.option(Rng::bytes),
+                    created_at_time: self.option(Rng::next),
+                },
+                TxKind::Burn {
+                    token_id: self.nat(),
+                    from_tx: self.nat(),
+                    memo: self.option(Rng::bytes),
+                    created_at_time: self.option(Rng::next),
+                },
+                TxKind::Approve {
+                    token_id: self.nat(),
+                    spender: self.principal(),
+                    approved: self.bool(),
+                    from_tx: self.nat(),
+                    memo: self.option(Rng::bytes),
+                    created_at_time: self.option(Rng::next),
+                },
+                TxKind::ApproveForAll {
+                    operator: self.principal(),
+                    approved: self.bool(),
+                    subaccount: self.option(|rng| Subaccount(rng.bytes())),
+                    memo: self.option(Rng::bytes),
+                    created_at_time: self.option(Rng::next),
+                },
+                TxKind::SetCustodian {
+                    custodian: self.principal(),
+                    approved: self.bool(),
+                    from_tx: self.nat(),
+                },
+                TxKind::SetMetadata {
+                    key: format!("{}", self.next()),
+                    value: self.value(),
+                    from_tx: self.nat(),
+                },
+                TxKind::RemoveMetadata {
+                    key: format!("{}", self.next()),
+                    from_tx: self.nat(),
+                },
+                TxKind::FreezeMetadata {
+                    key: format!("{}", self.next()),
+                    from_tx: self.nat(),
+                },
+                TxKind::SetRoyaltyFee {
+                    token_id: self.option(Rng::nat),
+                    account: self.account(),
+                    fee: self.nat(),
+                },
+            ]
+        }
+    }
+
+    #[test]
+    fn event_round_trip() {
+        let mut rng = Rng(42);
+        for _ in 0..100 {
+            let caller = rng.principal();
+            let time = rng.next();
+            for kind in rng.tx_kinds() {
+                let event = kind.clone().into_event(caller, time);
+                assert_eq!((event.caller, event.operation.as_str(), event.time), (caller, kind.operation(), time));
+                assert_eq!(TxKind::try_from_event(&event), Ok(kind));
+            }
+        }
+    }
+
+    #[test]
+    fn operations_are_tx_kinds() {
+        let kinds = Rng(3).tx_kinds();
+        for operation in OPERATIONS {
+            assert!(kinds.iter().any(|kind| kind.operation() == *operation), "{} is not a transaction kind", operation);
+        }
+    }
+
+    #[test]
+    fn parent_hash_is_ignored() {
+        let mut rng = Rng(7);
+        for _ in 0..100 {
+            for kind in rng.tx_kinds() {
+                let mut event = kind.clone().into_event(rng.principal(), rng.next());
+                event.details.insert(PARENT_HASH_KEY.into(), Value::Blob(Vec::from(rng.bytes())));
+                assert_eq!(TxKind::try_from_event(&event), Ok(kind));
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_details_are_rejected() {
+        let mut rng = Rng(1234);
+        for _ in 0..100 {
+            for kind in rng.tx_kinds() {
+                let mut event = kind.into_event(rng.principal(), rng.next());
+                let key = format!("unknown_{}", rng.next());
+                event.details.insert(key.clone(), rng.value());
+                assert_eq!(TxKind::try_from_event(&event), Err(ParseEventError::UnexpectedDetail(key)));
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_events_are_rejected() {
+        let mut rng = Rng(99);
+        let transfer = rng.tx_kinds().into_iter().find(|kind| kind.operation() == TRANSFER).unwrap();
+        let mut event = transfer.clone().into_event(rng.principal(), rng.next());
+        event.operation = "sld1:unknown".into();
+        assert_eq!(TxKind::try_from_event(&event), Err(ParseEventError::UnknownOperation("sld1:unknown".into())));
+
+        let mut event = transfer.clone().into_event(rng.principal(), rng.next());
+        event.details.remove("from_tx");
+        assert_eq!(TxKind::try_from_event(&event), Err(ParseEventError::MissingDetail("from_tx".into())));
+
+        let mut event = transfer.into_event(rng.principal(), rng.next());
+        event.details.insert("token_id".into(), Value::Text("1".into()));
+        assert_eq!(TxKind::try_from_event(&event), Err(ParseEventError::InvalidDetail("token_id".into())));
+    }
+}
@@ -1,25 +1,85 @@
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::convert::TryInto;
 use std::fmt;
 use std::fmt::Write;
+use std::str::FromStr;
 
 use candid::{Func, Int, Nat, Principal};
 use ic_cdk::export::candid::CandidType;
 use ic_cdk::id;
-use num_traits::ToPrimitive;
-use serde::{Deserialize, Serialize, Serializer};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use serde_bytes::ByteBuf;
 
 use crate::rc_bytes::RcBytes;
 
 pub type TokenId = Nat;
 
-#[derive(Clone, Debug, CandidType, Deserialize)]
+/// Value of metadata and transaction details, arrays and maps can hold nested values.
+/// Variants are only ever added, so that values of earlier versions can still be decoded.
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
 pub enum Value {
     Nat(Nat),
     Int(Int),
     Text(String),
     Blob(Vec<u8>),
+    Array(Vec<Value>),
+    Map(HashMap<String, Value>),
+}
+
+impl Value {
+    pub fn as_nat(&self) -> Option<&Nat> {
+        match self {
+            Value::Nat(value) => Some(value),
+            _ => None
+        }
+    }
+
+    pub fn as_int(&self) -> Option<&Int> {
+        match self {
+            Value::Int(value) => Some(value),
+            _ => None
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Value::Text(value) => Some(value),
+            _ => None
+        }
+    }
+
+    pub fn as_blob(&self) -> Option<&[u8]> {
+        match self {
+            Value::Blob(value) => Some(value),
+            _ => None
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&HashMap<String, Value>> {
+        match self {
+            Value::Map(values) => Some(values),
+            _ => None
+        }
+    }
+
+    /// Principal that is stored as text
+    pub fn as_principal(&self) -> Option<Principal> {
+        Principal::from_text(self.as_text()?).ok()
+    }
+
+    /// Account that is stored as text
+    pub fn as_account(&self) -> Option<Account> {
+        self.as_text()?.parse().ok()
+    }
 }
 
 /// Subaccount is an arbitrary 32-byte byte array.
@@ -30,7 +90,7 @@ pub struct Subaccount(pub [u8; 32]);
 
 /// Account follow ICRC-1 standard
 #[derive(
-CandidType, Deserialize, Clone, Copy, Hash, Debug, Eq, PartialOrd, Ord,
+CandidType, Clone, Copy, Debug, Eq,
 )]
 pub struct Account {
     pub owner: Principal,
@@ -59,7 +119,8 @@ pub struct GenericError {
 pub enum TransferError {
     NotFound,
     NotOwner,
-    NotSelf,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
     TemporarilyUnavailable,
     GenericError(GenericError),
 }
@@ -70,6 +131,29 @@ pub struct SupportedStandard {
     pub url: String,
 }
 
+#[cfg(feature = "sld2")]
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SetApprovalArgs {
+    pub from_subaccount: Option<Subaccount>,
+    pub spender: Principal,
+    pub token_id: TokenId,
+    pub approved: bool,
+    pub memo: Option<[u8; 32]>,
+    pub created_at_time: Option<u64>,
+}
+
+#[cfg(feature = "sld2")]
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum SetApprovalError {
+    NotFound,
+    NotOwner,
+    MaxApprovals(Nat),
+    TemporarilyUnavailable,
+    GenericError(GenericError),
+}
+
+/// Deprecated, replaced by `SetApprovalArgs`
+#[cfg(feature = "sld2")]
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct ApproveArgs {
     pub from_subaccount: Option<Subaccount>,
@@ -80,6 +164,8 @@ pub struct ApproveArgs {
     pub created_at_time: Option<u64>,
 }
 
+/// Deprecated, replaced by `SetApprovalError`
+#[cfg(feature = "sld2")]
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub enum ApproveError {
     NotFound,
@@ -90,6 +176,24 @@ pub enum ApproveError {
     GenericError(GenericError),
 }
 
+#[cfg(feature = "sld2")]
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SetApprovalForAllArgs {
+    pub from_subaccount: Option<Subaccount>,
+    pub operator: Principal,
+    pub approved: bool,
+    pub memo: Option<[u8; 32]>,
+    pub created_at_time: Option<u64>,
+}
+
+#[cfg(feature = "sld2")]
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum SetApprovalForAllError {
+    MaxApprovals(Nat),
+    TemporarilyUnavailable,
+    GenericError(GenericError),
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct TransferFromArgs {
     pub from: Account,
@@ -103,7 +207,6 @@ pub struct TransferFromArgs {
 pub enum TransferFromError {
     NotFound,
     NotOwner,
-    NotSelf,
     NotApproved,
     TemporarilyUnavailable,
     GenericError(GenericError),
@@ -114,7 +217,6 @@ impl TransferFromError {
         match self {
             TransferFromError::NotFound => TransferError::NotFound,
             TransferFromError::NotOwner => TransferError::NotOwner,
-            TransferFromError::NotSelf => TransferError::NotSelf,
             TransferFromError::NotApproved => TransferError::GenericError(GenericError {
                 error_code: Nat::from(403),
                 message: "Caller is not approved".into(),
@@ -125,6 +227,108 @@ impl TransferFromError {
     }
 }
 
+/// Errors of transactions with a created at time that are either
+/// outside of the transaction window or have already been submitted.
+#[derive(Clone, Debug)]
+pub enum TxError {
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+}
+
+/// Only SLD-1 transfers define errors for the transaction window, other
+/// operations and duplicates are rejected with a generic error instead.
+impl From<TxError> for GenericError {
+    fn from(err: TxError) -> Self {
+        match err {
+            TxError::TooOld => GenericError {
+                error_code: Nat::from(400),
+                message: "Transaction is too old".into(),
+            },
+            TxError::CreatedInFuture { ledger_time } => GenericError {
+                error_code: Nat::from(400),
+                message: format!("Transaction is created in the future, ledger time is {}", ledger_time),
+            },
+            TxError::Duplicate { duplicate_of } => GenericError {
+                error_code: Nat::from(409),
+                message: format!("Transaction is a duplicate of {}", duplicate_of),
+            },
+        }
+    }
+}
+
+/// SLD-1 defines its own errors for transactions outside of the window
+impl From<TxError> for TransferError {
+    fn from(err: TxError) -> Self {
+        match err {
+            TxError::TooOld => TransferError::TooOld,
+            TxError::CreatedInFuture { ledger_time } => TransferError::CreatedInFuture { ledger_time },
+            TxError::Duplicate { .. } => TransferError::GenericError(err.into()),
+        }
+    }
+}
+
+#[cfg(feature = "sld2")]
+impl From<TxError> for SetApprovalError {
+    fn from(err: TxError) -> Self {
+        SetApprovalError::GenericError(err.into())
+    }
+}
+
+#[cfg(feature = "sld2")]
+impl From<ApproveArgs> for SetApprovalArgs {
+    fn from(args: ApproveArgs) -> Self {
+        SetApprovalArgs {
+            from_subaccount: args.from_subaccount,
+            spender: args.spender,
+            token_id: args.token_id,
+            approved: args.approved,
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+        }
+    }
+}
+
+#[cfg(feature = "sld2")]
+impl From<SetApprovalError> for ApproveError {
+    fn from(err: SetApprovalError) -> Self {
+        match err {
+            SetApprovalError::NotFound => ApproveError::NotFound,
+            SetApprovalError::NotOwner => ApproveError::NotOwner,
+            SetApprovalError::MaxApprovals(max_approvals) => ApproveError::MaxApprovals(max_approvals),
+            SetApprovalError::TemporarilyUnavailable => ApproveError::TemporarilyUnavailable,
+            SetApprovalError::GenericError(generic_error) => ApproveError::GenericError(generic_error),
+        }
+    }
+}
+
+#[cfg(feature = "sld2")]
+impl From<TxError> for SetApprovalForAllError {
+    fn from(err: TxError) -> Self {
+        SetApprovalForAllError::GenericError(err.into())
+    }
+}
+
+#[cfg(feature = "sld4")]
+impl From<TxError> for MintError {
+    fn from(err: TxError) -> Self {
+        MintError::GenericError(err.into())
+    }
+}
+
+#[cfg(feature = "sld5")]
+impl From<TxError> for BurnError {
+    fn from(err: TxError) -> Self {
+        BurnError::GenericError(err.into())
+    }
+}
+
+impl From<TxError> for TransferFromError {
+    fn from(err: TxError) -> Self {
+        TransferFromError::GenericError(err.into())
+    }
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct Event {
     pub caller: Principal,
@@ -133,6 +337,7 @@ pub struct Event {
     pub details: HashMap<String, Value>,
 }
 
+#[cfg(feature = "sld3")]
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub enum EventOrBucket {
     Event(Event),
@@ -159,54 +364,164 @@ pub enum SetCustodiansError {
     GenericError(GenericError),
 }
 
-/// Internal Token state
 #[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SetMetadataArgs {
+    pub key: String,
+    pub value: Value,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum SetMetadataError {
+    NotAllowed,
+    InvalidKey,
+    Frozen,
+    TemporarilyUnavailable,
+    GenericError(GenericError),
+}
+
+/// Certificate of the certified data together with the hash tree that it
+/// certifies, the tree is CBOR encoded as defined in the interface spec.
+#[cfg(feature = "sld3")]
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DataCertificate {
+    pub certificate: ByteBuf,
+    pub hash_tree: ByteBuf,
+}
+
+#[cfg(feature = "sld3")]
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum AddBlockError {
+    NotAllowed,
+    TemporarilyUnavailable,
+    GenericError(GenericError),
+}
+
+#[cfg(feature = "sld3")]
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum OffloadBlocksError {
+    NotAllowed,
+    TemporarilyUnavailable,
+    GenericError(GenericError),
+}
+
+/// Canister init arguments, limits that are not given fall back to their default
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct InitArgs {
+    pub name: String,
+    pub symbol: String,
+    pub logo: Option<String>,
+    pub metadata: HashMap<String, Value>,
+    pub custodians: Vec<Principal>,
+    pub max_supply: Option<Nat>,
+    pub max_approvals: Option<Nat>,
+    pub max_custodians: Option<Nat>,
+    pub page_size: Option<Nat>,
+    pub block_size: Option<Nat>,
+    pub tx_window: Option<u64>,
+    pub permitted_drift: Option<u64>,
+}
+
+/// Canister upgrade arguments, only the limits that are given are changed. The block
+/// size can't be changed since it's used to look up transactions in sealed blocks.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct UpgradeArgs {
+    pub max_supply: Option<Nat>,
+    pub max_approvals: Option<Nat>,
+    pub max_custodians: Option<Nat>,
+    pub page_size: Option<Nat>,
+    pub tx_window: Option<u64>,
+    pub permitted_drift: Option<u64>,
+}
+
+/// Internal Token state
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
 pub struct Token {
     pub account: Account,
     pub tx_id: Nat,
     pub approved: HashSet<Principal>,
+    pub metadata: HashMap<String, Value>,
+    pub royalty: Option<Royalty>,
 }
 
-pub type Offset = Nat;
-pub type Time = u64;
-
-/// History entry has a token id, account, time and offset of previous entry for this token id.
-///
-/// A token is (pre-)minted when previous offset is equal to current offset or if previous
-/// history entry was a burn. It's either a mint or pre-mint depending on the receiving
-/// account, it's a pre-mint if the account is the minter account. In case of a pre-mint,
-/// the next transfer to an account other than the minter account is considered a mint.
-///
-/// A burn occurs when the previous offset is not equal to current offset
-/// and the current account is equal to the minter account.
-///
-/// The minter account is always the canister principal.
-#[derive(CandidType, Clone, Serialize, Deserialize)]
-pub struct HistoryEntry {
-    pub token_id: TokenId,
+/// Royalty configuration with the fee in basis points of the sale price
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct Royalty {
+    pub account: Account,
+    pub fee: u16,
+}
+
+/// Royalty fee amount that should be paid to the account for a given sale price
+#[cfg(feature = "sld7")]
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RoyaltyFee {
+    pub account: Account,
+    pub fee: Nat,
+}
+
+/// Fee is in basis points, royalty is set for the whole collection when no token id is given
+#[cfg(feature = "sld8")]
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SetRoyaltyFeeArgs {
+    pub token_id: Option<TokenId>,
     pub account: Account,
-    pub time: Time,
-    pub from_offset: Offset,
+    pub fee: Nat,
+}
+
+#[cfg(feature = "sld8")]
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum SetRoyaltyFeeError {
+    NotAllowed,
+    TemporarilyUnavailable,
+    GenericError(GenericError),
+}
+
+#[cfg(feature = "sld4")]
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct MintArgs {
+    pub to: Account,
+    pub token_id: TokenId,
+    pub metadata: HashMap<String, Value>,
+    pub memo: Option<[u8; 32]>,
+    pub created_at_time: Option<u64>,
+}
+
+#[cfg(feature = "sld4")]
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum MintError {
+    NotAllowed,
+    TemporarilyUnavailable,
+    GenericError(GenericError),
+}
+
+#[cfg(feature = "sld5")]
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct BurnArgs {
+    pub token_id: TokenId,
+    pub memo: Option<[u8; 32]>,
+    pub created_at_time: Option<u64>,
+}
+
+#[cfg(feature = "sld5")]
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum BurnError {
+    NotAllowed,
+    TemporarilyUnavailable,
+    GenericError(GenericError),
 }
 
-// #[derive(Clone, Debug, CandidType, Deserialize)]
-// pub struct MintArgs {
-//     pub to: Account,
-//     pub token_id: TokenId,
-// }
 impl Account {
     /// Account with default subaccount should always default to account without subaccount
     pub fn new(owner: Principal, subaccount: Option<Subaccount>) -> Self {
         Account {
             owner,
-            subaccount: subaccount.map(|s| if s == DEFAULT_SUBACCOUNT { None } else { Some(s) }).flatten(),
+            subaccount: subaccount.and_then(|s| if s == DEFAULT_SUBACCOUNT { None } else { Some(s) }),
         }
     }
 
     /// Minter account is equal to canister principal
-    pub fn minter() -> Self {
+    pub fn minter(canister: Principal) -> Self {
         Account {
-            owner: id(),
+            owner: canister,
             subaccount: None,
         }
     }
@@ -222,6 +537,105 @@ impl Serialize for Account {
     }
 }
 
+/// Account should be parsed from ICRC-1 textual encoding when deserialized from JSON, binary
+/// formats like Candid keep deserializing the account as a record with owner and subaccount.
+impl<'de> Deserialize<'de> for Account {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(rename = "Account")]
+        struct AccountRecord {
+            owner: Principal,
+            subaccount: Option<Subaccount>,
+        }
+
+        if deserializer.is_human_readable() {
+            String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+        } else {
+            AccountRecord::deserialize(deserializer).map(|account| Account {
+                owner: account.owner,
+                subaccount: account.subaccount,
+            })
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseAccountError {
+    /// Text is not base32 encoded
+    Base32,
+    /// Decoded bytes are too short to contain a checksum
+    TooShort,
+    /// Checksum does not match the account bytes
+    Checksum,
+    /// Subaccount length byte before the `0x7f` identifier is out of bounds
+    SubaccountLength,
+    /// Owner bytes are not a valid principal
+    Principal,
+    /// Text is valid but not in the canonical form that would be returned by `to_string`
+    NotCanonical,
+}
+
+impl fmt::Display for ParseAccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ParseAccountError::Base32 => "Account is not base32 encoded",
+            ParseAccountError::TooShort => "Account is too short to contain a checksum",
+            ParseAccountError::Checksum => "Account checksum does not match",
+            ParseAccountError::SubaccountLength => "Account subaccount length is invalid",
+            ParseAccountError::Principal => "Account owner is not a valid principal",
+            ParseAccountError::NotCanonical => "Account is not in canonical textual encoding",
+        })
+    }
+}
+
+/// Inverse of ICRC-1 textual encoding, only the canonical encoding is accepted
+impl FromStr for Account {
+    type Err = ParseAccountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = data_encoding::BASE32_NOPAD
+            .decode(s.replace('-', "").to_ascii_uppercase().as_bytes())
+            .map_err(|_| ParseAccountError::Base32)?;
+        if bytes.len() < 4 {
+            return Err(ParseAccountError::TooShort);
+        }
+        let (checksum, bytes) = bytes.split_at(4);
+        if checksum != crc32fast::hash(bytes).to_be_bytes() {
+            return Err(ParseAccountError::Checksum);
+        }
+
+        // Bytes ending with the ICRC-1 non-default subaccount identifier are
+        // preceded by the subaccount length and the subaccount bytes itself.
+        let (owner, subaccount) = match bytes {
+            [rest @ .., count, 127] => {
+                let count = *count as usize;
+                if count == 0 || count > 32 || count > rest.len() {
+                    return Err(ParseAccountError::SubaccountLength);
+                }
+                let (owner, subaccount_bytes) = rest.split_at(rest.len() - count);
+                let mut subaccount = DEFAULT_SUBACCOUNT;
+                subaccount.0[32 - count..].copy_from_slice(subaccount_bytes);
+                (owner, Some(subaccount))
+            }
+            _ => (bytes, None),
+        };
+        let account = Account {
+            owner: Principal::try_from_slice(owner).map_err(|_| ParseAccountError::Principal)?,
+            subaccount,
+        };
+
+        // Leading zeros in the subaccount, a missing dash or uppercase
+        // characters are valid base32 but not the canonical encoding.
+        if account.to_string() != s {
+            return Err(ParseAccountError::NotCanonical);
+        }
+        Ok(account)
+    }
+}
+
 /// ICRC-1 textual encoding
 impl fmt::Display for Account {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -273,6 +687,27 @@ impl PartialEq for Account {
     }
 }
 
+/// Hash and ordering should be consistent with the custom equality check above,
+/// else accounts can't be reliably used as keys in maps and sets.
+impl Hash for Account {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.owner.hash(state);
+        self.subaccount.unwrap_or(DEFAULT_SUBACCOUNT).hash(state);
+    }
+}
+
+impl PartialOrd for Account {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Account {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.owner.cmp(&other.owner).then_with(|| self.subaccount.unwrap_or(DEFAULT_SUBACCOUNT).cmp(&other.subaccount.unwrap_or(DEFAULT_SUBACCOUNT)))
+    }
+}
+
 pub trait MintIndex {
     fn from_mint_index(mint: u32) -> Self;
     fn to_mint_index(&self) -> u32;
@@ -296,42 +731,6 @@ impl MintIndex for Principal {
     }
 }
 
-pub trait StableBytes<T> {
-    fn from_stable_bytes(bytes: &T) -> Self;
-    fn to_stable_bytes(&self, buf: &mut T);
-}
-
-
-/// Fixed size of history entry in stable memory
-pub const HISTORY_ENTRY_BYTES: usize = 78;
-
-/// Convert history entry into fixed length bytes for storage in stable memory
-impl StableBytes<[u8; HISTORY_ENTRY_BYTES]> for HistoryEntry {
-    fn from_stable_bytes(bytes: &[u8; HISTORY_ENTRY_BYTES]) -> Self {
-        let length = bytes[4] as usize;
-        let subaccount = Subaccount((&bytes[34..66]).try_into().unwrap());
-        HistoryEntry {
-            token_id: TokenId::from(u32::from_le_bytes((&bytes[0..4]).try_into().unwrap())),
-            account: Account {
-                owner: Principal::from_slice((&bytes[5..5 + length]).try_into().unwrap()),
-                subaccount: if subaccount == DEFAULT_SUBACCOUNT { None } else { Some(subaccount) },
-            },
-            time: Time::from(u64::from_le_bytes((&bytes[66..74]).try_into().unwrap())),
-            from_offset: Offset::from(u32::from_le_bytes((&bytes[74..78]).try_into().unwrap())),
-        }
-    }
-
-    fn to_stable_bytes(&self, buf: &mut [u8; HISTORY_ENTRY_BYTES]) {
-        // buf[0..4].copy_from_slice(&self.token_id.0.to_u32().unwrap().to_le_bytes());
-        // let length = self.account.owner.as_slice().len();
-        // buf[4] = length as u8;
-        // buf[5..5 + length].copy_from_slice(&self.account.owner.as_slice());
-        // buf[34..66].copy_from_slice(&self.account.subaccount.unwrap_or(DEFAULT_SUBACCOUNT).0);
-        // buf[66..74].copy_from_slice(&self.time.0.to_u64().unwrap().to_le_bytes());
-        // buf[74..78].copy_from_slice(&self.from_offset.0.to_u32().unwrap().to_le_bytes());
-    }
-}
-
 // HTTP interface
 
 pub type HeaderField = (String, String);
@@ -355,11 +754,31 @@ pub struct HttpResponse {
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub enum StreamingStrategy {
     Callback {
-        callback: Func,
+        callback: StreamingCallback,
         token: StreamingCallbackToken,
     },
 }
 
+/// Query method that returns the next chunk, typed so that the exported
+/// interface tells clients which arguments the callback expects.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(transparent)]
+pub struct StreamingCallback(pub Func);
+
+impl CandidType for StreamingCallback {
+    fn _ty() -> candid::types::Type {
+        candid::types::Type::Func(candid::types::Function {
+            modes: vec![candid::parser::types::FuncMode::Query],
+            args: vec![StreamingCallbackToken::ty()],
+            rets: vec![StreamingCallbackHttpResponse::ty()],
+        })
+    }
+
+    fn idl_serialize<S: candid::types::Serializer>(&self, serializer: S) -> Result<(), S::Error> {
+        self.0.idl_serialize(serializer)
+    }
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct StreamingCallbackToken {
     pub key: String,
@@ -373,4 +792,83 @@ pub struct StreamingCallbackToken {
 pub struct StreamingCallbackHttpResponse {
     pub body: RcBytes,
     pub token: Option<StreamingCallbackToken>,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Owner of the golden vectors, the ICP ledger canister
+    fn owner() -> Principal {
+        Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap()
+    }
+
+    fn subaccount(bytes: &[u8]) -> Subaccount {
+        let mut subaccount = DEFAULT_SUBACCOUNT;
+        subaccount.0[32 - bytes.len()..].copy_from_slice(bytes);
+        subaccount
+    }
+
+    #[test]
+    fn parse_account() {
+        let full: Vec<u8> = (1..=32).collect();
+        let vectors = [
+            ("ryjl3-tyaaa-aaaaa-aaaba-cai", Account { owner: owner(), subaccount: None }),
+            ("d4bz5-byaaa-aaaaa-aaaba-caiba-f7q", Account { owner: owner(), subaccount: Some(subaccount(&[1])) }),
+            ("c6bef-nqaaa-aaaaa-aaaba-caiba-ibqib-iga4e-ascql-bqgq4-dyqce-jbgfa-vcylr-qgi2d-mob2h-q7eaq-h6", Account { owner: owner(), subaccount: Some(subaccount(&full)) }),
+        ];
+        for (text, account) in vectors {
+            let parsed: Account = text.parse().unwrap();
+            assert_eq!(parsed, account);
+            assert_eq!(parsed.subaccount, account.subaccount);
+            assert_eq!(account.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn parse_account_errors() {
+        let vectors = [
+            ("e4bz5-byaaa-aaaaa-aaaba-caiba-f7q", ParseAccountError::Checksum),
+            ("D4BZ5-BYAAA-AAAAA-AAABA-CAIBA-F7Q", ParseAccountError::NotCanonical),
+            ("d4bz5byaaaaaaaaaaabacaibaf7q", ParseAccountError::NotCanonical),
+            // Subaccount with a leading zero byte
+            ("inlpn-yyaaa-aaaaa-aaaba-caiaa-ebh6", ParseAccountError::NotCanonical),
+            // Subaccount length byte of 0 and 33
+            ("pdi7j-4aaaa-aaaaa-aaaba-caiap-4", ParseAccountError::SubaccountLength),
+            ("l5c5b-ziaaa-aaaaa-aaaba-caiaa-aaaaa-aaaaa-aaaaa-aaaaa-aaaaa-aaaaa-aaaaa-aaaaa-aaaaa-aaaaa-cc7y", ParseAccountError::SubaccountLength),
+            ("ryjl3-tyaaa-aaaaa-aaaba-ca1", ParseAccountError::Base32),
+            ("aaaaa", ParseAccountError::TooShort),
+        ];
+        for (text, err) in vectors {
+            assert_eq!(text.parse::<Account>(), Err(err), "{}", text);
+        }
+    }
+
+    #[test]
+    fn account_round_trip() {
+        let accounts = [
+            Account::new(owner(), None),
+            Account::new(owner(), Some(subaccount(&[1, 0, 0]))),
+            Account::new(Principal::management_canister(), Some(subaccount(&[255; 32]))),
+            Account::new(Principal::anonymous(), Some(subaccount(&[7; 5]))),
+        ];
+        for account in accounts {
+            assert_eq!(account.to_string().parse::<Account>(), Ok(account));
+        }
+
+        // Default subaccount is encoded the same as no subaccount
+        let account = Account { owner: owner(), subaccount: Some(DEFAULT_SUBACCOUNT) };
+        assert_eq!(account.to_string(), "ryjl3-tyaaa-aaaaa-aaaba-cai");
+    }
+
+    #[test]
+    fn deserialize_account() {
+        let account = Account { owner: owner(), subaccount: Some(subaccount(&[1])) };
+        assert_eq!(serde_json::to_string(&account).unwrap(), "\"d4bz5-byaaa-aaaaa-aaaba-caiba-f7q\"");
+        assert_eq!(serde_json::from_str::<Account>("\"d4bz5-byaaa-aaaaa-aaaba-caiba-f7q\"").unwrap(), account);
+        assert!(serde_json::from_str::<Account>("\"e4bz5-byaaa-aaaaa-aaaba-caiba-f7q\"").is_err());
+
+        // Candid keeps the record with owner and subaccount
+        let decoded: Account = candid::decode_one(&candid::encode_one(account).unwrap()).unwrap();
+        assert_eq!(decoded.subaccount, account.subaccount);
+    }
+}